@@ -3,16 +3,33 @@ CREATE DATABASE markov;
 USE markov;
 
 CREATE TABLE IF NOT EXISTS `markov_cache` (
+  `user_id`     CHAR(36) NOT NULL,
   `cache`       LONGTEXT NOT NULL,
-  `last_update` DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+  `last_update` DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (user_id)
 ) ENGINE = InnoDB DEFAULT CHARSET = utf8mb4 COLLATE = utf8mb4_unicode_ci;
 
 CREATE TABLE IF NOT EXISTS `messages` (
   `id`         CHAR(36) NOT NULL,
+  `user_id`    CHAR(36) NOT NULL,
   `channel_id` CHAR(36) NOT NULL,
   `content`    TEXT NOT NULL,
   `created_at` DATETIME NOT NULL,
-  PRIMARY KEY (id)
+  `learned`    BOOLEAN NOT NULL DEFAULT FALSE,
+  `streamed`   BOOLEAN NOT NULL DEFAULT FALSE,
+  `deleted`    BOOLEAN NOT NULL DEFAULT FALSE,
+  PRIMARY KEY (id),
+  INDEX (user_id)
+) ENGINE = InnoDB DEFAULT CHARSET = utf8mb4 COLLATE = utf8mb4_unicode_ci;
+
+CREATE TABLE IF NOT EXISTS `fetch_windows` (
+  `user_id`    CHAR(36) NOT NULL,
+  `since`      DATETIME NOT NULL,
+  `until`      DATETIME NOT NULL,
+  `total_hits` INTEGER NOT NULL,
+  `fetched`    INTEGER NOT NULL DEFAULT 0,
+  `done`       BOOLEAN NOT NULL DEFAULT FALSE,
+  PRIMARY KEY (user_id, since)
 ) ENGINE = InnoDB DEFAULT CHARSET = utf8mb4 COLLATE = utf8mb4_unicode_ci;
 
 CREATE TABLE IF NOT EXISTS `frequency` (
@@ -20,3 +37,28 @@ CREATE TABLE IF NOT EXISTS `frequency` (
   `frequency`  INTEGER NOT NULL DEFAULT 0,
   PRIMARY KEY (channel_id)
 ) ENGINE = InnoDB DEFAULT CHARSET = utf8mb4 COLLATE = utf8mb4_unicode_ci;
+
+CREATE TABLE IF NOT EXISTS `schedules` (
+  `id`             BIGINT NOT NULL AUTO_INCREMENT,
+  `channel_id`     CHAR(36) NOT NULL,
+  `cron`           VARCHAR(255) NOT NULL,
+  `jitter_minutes` INTEGER NOT NULL DEFAULT 0,
+  PRIMARY KEY (id),
+  INDEX (channel_id)
+) ENGINE = InnoDB DEFAULT CHARSET = utf8mb4 COLLATE = utf8mb4_unicode_ci;
+
+CREATE TABLE IF NOT EXISTS `quiet_hours` (
+  `channel_id` CHAR(36) NOT NULL,
+  `start`      TIME NOT NULL,
+  `end`        TIME NOT NULL,
+  PRIMARY KEY (channel_id)
+) ENGINE = InnoDB DEFAULT CHARSET = utf8mb4 COLLATE = utf8mb4_unicode_ci;
+
+CREATE TABLE IF NOT EXISTS `roles` (
+  `user_id`    CHAR(36) NOT NULL,
+  -- 空文字列ならすべてのチャンネル
+  `channel_id` VARCHAR(36) NOT NULL DEFAULT '',
+  `role`       VARCHAR(16) NOT NULL,
+  PRIMARY KEY (user_id, channel_id),
+  INDEX (channel_id)
+) ENGINE = InnoDB DEFAULT CHARSET = utf8mb4 COLLATE = utf8mb4_unicode_ci;
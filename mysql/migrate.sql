@@ -0,0 +1,62 @@
+-- 既存の DB を mysql/init/schema.sql のスキーマに揃える
+-- 何度実行しても同じ結果になる
+USE markov;
+
+-- 複数の人格に対応する前のメッセージはすべて 81bbc211-65aa-4a45-8c56-e0b78d25f9e5 のもの
+ALTER TABLE `messages`
+  ADD COLUMN IF NOT EXISTS `user_id` CHAR(36) NOT NULL DEFAULT '81bbc211-65aa-4a45-8c56-e0b78d25f9e5' AFTER `id`,
+  ADD COLUMN IF NOT EXISTS `learned`  BOOLEAN NOT NULL DEFAULT FALSE,
+  ADD COLUMN IF NOT EXISTS `streamed` BOOLEAN NOT NULL DEFAULT FALSE,
+  ADD COLUMN IF NOT EXISTS `deleted`  BOOLEAN NOT NULL DEFAULT FALSE,
+  ADD INDEX IF NOT EXISTS `user_id` (`user_id`);
+ALTER TABLE `messages` ALTER COLUMN `user_id` DROP DEFAULT;
+
+-- user_id のない markov chain はメッセージから作り直すので消す
+ALTER TABLE `markov_cache`
+  ADD COLUMN IF NOT EXISTS `user_id` CHAR(36) NOT NULL DEFAULT '' FIRST;
+DELETE FROM `markov_cache` WHERE `user_id` = '';
+ALTER TABLE `markov_cache`
+  ALTER COLUMN `user_id` DROP DEFAULT,
+  DROP INDEX IF EXISTS `PRIMARY`,
+  ADD PRIMARY KEY (`user_id`);
+
+CREATE TABLE IF NOT EXISTS `fetch_windows` (
+  `user_id`    CHAR(36) NOT NULL,
+  `since`      DATETIME NOT NULL,
+  `until`      DATETIME NOT NULL,
+  `total_hits` INTEGER NOT NULL,
+  `fetched`    INTEGER NOT NULL DEFAULT 0,
+  `done`       BOOLEAN NOT NULL DEFAULT FALSE,
+  PRIMARY KEY (user_id, since)
+) ENGINE = InnoDB DEFAULT CHARSET = utf8mb4 COLLATE = utf8mb4_unicode_ci;
+
+CREATE TABLE IF NOT EXISTS `frequency` (
+  `channel_id` CHAR(36) NOT NULL,
+  `frequency`  INTEGER NOT NULL DEFAULT 0,
+  PRIMARY KEY (channel_id)
+) ENGINE = InnoDB DEFAULT CHARSET = utf8mb4 COLLATE = utf8mb4_unicode_ci;
+
+CREATE TABLE IF NOT EXISTS `schedules` (
+  `id`             BIGINT NOT NULL AUTO_INCREMENT,
+  `channel_id`     CHAR(36) NOT NULL,
+  `cron`           VARCHAR(255) NOT NULL,
+  `jitter_minutes` INTEGER NOT NULL DEFAULT 0,
+  PRIMARY KEY (id),
+  INDEX (channel_id)
+) ENGINE = InnoDB DEFAULT CHARSET = utf8mb4 COLLATE = utf8mb4_unicode_ci;
+
+CREATE TABLE IF NOT EXISTS `quiet_hours` (
+  `channel_id` CHAR(36) NOT NULL,
+  `start`      TIME NOT NULL,
+  `end`        TIME NOT NULL,
+  PRIMARY KEY (channel_id)
+) ENGINE = InnoDB DEFAULT CHARSET = utf8mb4 COLLATE = utf8mb4_unicode_ci;
+
+CREATE TABLE IF NOT EXISTS `roles` (
+  `user_id`    CHAR(36) NOT NULL,
+  -- 空文字列ならすべてのチャンネル
+  `channel_id` VARCHAR(36) NOT NULL DEFAULT '',
+  `role`       VARCHAR(16) NOT NULL,
+  PRIMARY KEY (user_id, channel_id),
+  INDEX (channel_id)
+) ENGINE = InnoDB DEFAULT CHARSET = utf8mb4 COLLATE = utf8mb4_unicode_ci;
@@ -0,0 +1,630 @@
+use log::{error, info};
+use once_cell::sync::Lazy;
+
+use super::{describe, runnable_paths, Arg, Command, CommandContext, Reply, UsageError};
+use crate::{
+    config::config,
+    constraints::Purpose,
+    cron::{PostScheduler, CONFIG_POST_JITTER_MINUTES},
+    generate_reply,
+    model::db::{
+        get_channel_roles, get_channel_schedules, get_frequency, grant_role, revoke_role,
+        update_frequency, ScheduleRecord,
+    },
+    quiet::{get_quiet_hours_with_cache, set_quiet_hours, QuietHours},
+    reload::reload_config,
+    role::{Role, ALL_CHANNELS},
+    FREQUENCIES_CACHE, POOL,
+};
+
+/// 定期投稿を遅らせる最大の分数のデフォルト
+const DEFAULT_SCHEDULE_JITTER_MINUTES: i32 = 30;
+
+/// 定期投稿を遅らせる最大の分数の上限 (1 日)
+const MAX_SCHEDULE_JITTER_MINUTES: i32 = 24 * 60;
+
+/// メンションで使えるコマンド
+pub static COMMANDS: Lazy<Vec<Command>> = Lazy::new(|| {
+    vec![
+        Command::new("join", "このチャンネルに参加する", |ctx| {
+            Box::pin(join(ctx))
+        })
+        .bare()
+        .role(Role::ChannelAdmin),
+        Command::new("leave", "このチャンネルから退出する", |ctx| {
+            Box::pin(leave(ctx))
+        })
+        .bare()
+        .role(Role::ChannelAdmin),
+        Command::new(
+            "freq",
+            "このチャンネルでの返信頻度 (%) を変更する (off / full も使える、省略すると現在の頻度を表示する)",
+            |ctx| Box::pin(freq(ctx)),
+        )
+        .aliases(&["frequency"])
+        .role(Role::ChannelAdmin)
+        .query_without_args()
+        .args(vec![Arg::optional("頻度")])
+        .current(|ctx| Box::pin(current_freq(ctx))),
+        Command::group(
+            "schedule",
+            "このチャンネルへの定期投稿",
+            vec![
+                Command::new(
+                    "add",
+                    "cron 式の時刻に、最大の遅れ (分) までランダムに遅らせて投稿する",
+                    |ctx| Box::pin(schedule_add(ctx)),
+                )
+                .role(Role::ChannelAdmin)
+                .args(vec![
+                    Arg::required("cron 式"),
+                    Arg::optional("最大の遅れ (分)"),
+                ]),
+                Command::new("list", "定期投稿の一覧を表示する", |ctx| {
+                    Box::pin(schedule_list(ctx))
+                }),
+                Command::new("rm", "定期投稿を削除する", |ctx| {
+                    Box::pin(schedule_remove(ctx))
+                })
+                .aliases(&["remove"])
+                .role(Role::ChannelAdmin)
+                .args(vec![Arg::required("ID")]),
+            ],
+        )
+        .in_direct_message()
+        .current(|ctx| Box::pin(current_schedules(ctx))),
+        Command::new(
+            "quiet",
+            "このチャンネルで投稿を控える時間帯 (例: 01:00-07:00) を設定する (off で解除)",
+            |ctx| Box::pin(quiet(ctx)),
+        )
+        .role(Role::ChannelAdmin)
+        .query_without_args()
+        .args(vec![Arg::optional("時間帯")])
+        .current(|ctx| Box::pin(current_quiet(ctx))),
+        Command::new("reload", "設定を再読み込みする", |ctx| {
+            Box::pin(reload(ctx))
+        })
+        .role(Role::Owner),
+        Command::group(
+            "role",
+            "権限 (owner: オーナー, admin: このチャンネルの管理者)",
+            vec![
+                Command::new("list", "このチャンネルで権限を持つユーザーの一覧を表示する", |ctx| {
+                    Box::pin(role_list(ctx))
+                }),
+                Command::new("grant", "ユーザーに権限を付与する", |ctx| {
+                    Box::pin(role_grant(ctx))
+                })
+                .role(Role::Owner)
+                .args(vec![Arg::required("@ユーザー"), Arg::required("権限")]),
+                Command::new("revoke", "ユーザーの権限を剥奪する", |ctx| {
+                    Box::pin(role_revoke(ctx))
+                })
+                .role(Role::Owner)
+                .args(vec![Arg::required("@ユーザー"), Arg::required("権限")]),
+            ],
+        ),
+        Command::new(
+            "as",
+            "学習しているユーザーの人格でメッセージに返信する",
+            |ctx| Box::pin(reply_as(ctx)),
+        )
+        .args(vec![Arg::required("@ユーザー"), Arg::rest("メッセージ")]),
+        Command::new(
+            "settings",
+            "このチャンネルの返信頻度・投稿を控える時間帯・定期投稿などの設定を表示する",
+            |ctx| Box::pin(settings(ctx)),
+        )
+        .aliases(&["status"]),
+        Command::new(
+            "help",
+            "コマンドの一覧を表示する (コマンド名を指定するとそのコマンドだけ)",
+            |ctx| Box::pin(help(ctx)),
+        )
+        .aliases(&["h", "?"])
+        .in_direct_message()
+        .args(vec![Arg::optional("コマンド")]),
+    ]
+});
+
+/// コマンドの書き方と説明を、現在の値と一緒に一覧にする
+async fn help(ctx: CommandContext) -> Reply {
+    let commands = match ctx.arg(0) {
+        None => COMMANDS.iter().collect::<Vec<_>>(),
+        Some(name) => {
+            let name = name.trim_start_matches(['/', '\\']);
+            let Some(command) = COMMANDS.iter().find(|c| c.is_named(name)) else {
+                return Err(UsageError(format!("不明なコマンドです: {}", name)));
+            };
+            vec![command]
+        }
+    };
+
+    let mut lines =
+        vec!["コマンド一覧 (`@BOT_SSlime /freq 50` のようにメンションの後に書きます)".to_string()];
+    if ctx.direct_message {
+        let available = COMMANDS
+            .iter()
+            .filter(|c| c.direct_message)
+            .map(|c| format!("`/{}`", c.name))
+            .collect::<Vec<_>>();
+        lines.push(format!(
+            "DM では {} をメンションなしで使えます",
+            available.join(", ")
+        ));
+    }
+    for command in commands {
+        for path in runnable_paths(&[command]) {
+            lines.push(describe(&path));
+        }
+        if !command.aliases.is_empty() {
+            let aliases = command
+                .aliases
+                .iter()
+                .map(|a| format!("`/{}`", a))
+                .collect::<Vec<_>>();
+            lines.push(format!("    - 別名: {}", aliases.join(", ")));
+        }
+        // DM では、そのチャンネルの値は意味がないので表示しない
+        if ctx.direct_message {
+            continue;
+        }
+        if let Some(current) = command.current {
+            if let Some(value) = current(ctx.clone()).await {
+                lines.push(format!("    - 現在: {}", value));
+            }
+        }
+    }
+    Ok(Some(lines.join("\n")))
+}
+
+/// channel_id で実際に使われる返信頻度を、デフォルトの値かどうかと一緒に表示する
+async fn describe_freq(channel_id: &str) -> anyhow::Result<String> {
+    let res = match get_frequency(POOL.get().unwrap(), channel_id.to_string()).await? {
+        Some(record) => format!("{}% (このチャンネルで設定)", record.frequency),
+        None => format!("{}% (デフォルト)", config().default_freq),
+    };
+    Ok(res)
+}
+
+async fn current_freq(ctx: CommandContext) -> Option<String> {
+    describe_freq(&ctx.message.channel_id)
+        .await
+        .map_err(|e| error!("Failed to get frequency: {:#}", e))
+        .ok()
+}
+
+async fn current_schedules(ctx: CommandContext) -> Option<String> {
+    let schedules = get_channel_schedules(POOL.get().unwrap(), &ctx.message.channel_id)
+        .await
+        .map_err(|e| error!("Failed to get schedules: {:#}", e))
+        .ok()?;
+    Some(format!(
+        "{} 件 (cron 式は {} の時刻)",
+        schedules.len(),
+        ctx.resource.scheduler.tz()
+    ))
+}
+
+async fn current_quiet(ctx: CommandContext) -> Option<String> {
+    let quiet = get_quiet_hours_with_cache(POOL.get().unwrap(), &ctx.message.channel_id)
+        .await
+        .map_err(|e| error!("Failed to get quiet hours: {:#}", e))
+        .ok()?;
+    let value = match quiet {
+        Some(quiet) => format!("{} ({})", quiet, ctx.resource.scheduler.tz()),
+        None => "なし".to_string(),
+    };
+    Some(value)
+}
+
+/// 参加したら join_handler が投稿する
+async fn join(ctx: CommandContext) -> Reply {
+    let res = ctx.resource.api.join_channel(ctx.message.channel_id).await;
+    if let Err(e) = res {
+        error!("Failed to join channel: {}", e);
+    }
+    Ok(None)
+}
+
+/// 退出したら left_handler が投稿する
+async fn leave(ctx: CommandContext) -> Reply {
+    let res = ctx.resource.api.leave_channel(ctx.message.channel_id).await;
+    if let Err(e) = res {
+        error!("Failed to leave channel: {}", e);
+    }
+    Ok(None)
+}
+
+/// 引数がなければ現在の頻度を表示する
+async fn freq(ctx: CommandContext) -> Reply {
+    let Some(arg) = ctx.arg(0) else {
+        let res_msg = match describe_freq(&ctx.message.channel_id).await {
+            Ok(freq) => format!("このチャンネルの返信頻度は {} です", freq),
+            Err(e) => {
+                error!("Failed to get frequency: {:#}", e);
+                "頻度の取得に失敗しました :Hyperblob:".to_string()
+            }
+        };
+        return Ok(Some(res_msg));
+    };
+    let freq = match arg {
+        "off" | "no" => 0,
+        "full" => 100,
+        x => match x.parse::<i64>() {
+            Ok(freq) if (0..=100).contains(&freq) => freq,
+            Ok(_) => return Err(UsageError("不正な数値です (0~100 expected)".to_string())),
+            Err(_) => return Err(UsageError("不正な引数です (0~100 expected)".to_string())),
+        },
+    };
+
+    let channel_id = ctx.message.channel_id;
+    if let Err(e) = update_frequency(POOL.get().unwrap(), channel_id.clone(), freq).await {
+        error!("Failed to update frequency: {}", e);
+        return Ok(Some("頻度の更新に失敗しました :Hyperblob:".to_string()));
+    }
+    FREQUENCIES_CACHE.lock().unwrap().insert(channel_id, freq);
+    let res_msg = match freq {
+        0 => "返答をしないように設定しました :blob_pyon:".to_string(),
+        100 => "常に返答をするように設定しました :blob_pyon:".to_string(),
+        _ => format!("頻度を {}% に設定しました :blob_pyon:", freq),
+    };
+    Ok(Some(res_msg))
+}
+
+/// cron 式の次の時刻を、スケジューラーのタイムゾーンで表示する
+fn format_next_schedule(scheduler: &PostScheduler, cron: &str) -> String {
+    match scheduler.parse(cron).ok().and_then(|s| s.upcoming()) {
+        Some(next) => format!(
+            "次回 {} ({})",
+            next.format("%Y-%m-%d %H:%M:%S"),
+            next.timezone()
+        ),
+        None => "次回なし".to_string(),
+    }
+}
+
+/// `ID: cron 式 (最大の遅れ, 次回の時刻)`
+fn describe_schedule(scheduler: &PostScheduler, schedule: &ScheduleRecord) -> String {
+    format!(
+        "{}: `{}` (最大 {} 分遅れ, {})",
+        schedule.id,
+        schedule.cron,
+        schedule.jitter_minutes,
+        format_next_schedule(scheduler, &schedule.cron)
+    )
+}
+
+async fn schedule_add(ctx: CommandContext) -> Reply {
+    let cron = ctx.arg(0).unwrap().trim();
+    let jitter = match ctx.arg(1).map(str::parse::<i32>) {
+        None => DEFAULT_SCHEDULE_JITTER_MINUTES,
+        Some(Ok(jitter)) if (0..=MAX_SCHEDULE_JITTER_MINUTES).contains(&jitter) => jitter,
+        Some(_) => {
+            return Err(UsageError(format!(
+                "不正な遅れです (0~{} expected)",
+                MAX_SCHEDULE_JITTER_MINUTES
+            )))
+        }
+    };
+
+    let scheduler = &ctx.resource.scheduler;
+    let channel_id = &ctx.message.channel_id;
+    let res_msg = match scheduler
+        .add(POOL.get().unwrap(), channel_id, cron, jitter)
+        .await
+    {
+        Ok(id) => format!(
+            "定期投稿を追加しました :blob_pyon: (ID: {}, `{}`, 最大 {} 分遅れ, {})",
+            id,
+            cron,
+            jitter,
+            format_next_schedule(scheduler, cron)
+        ),
+        Err(e) => {
+            error!("Failed to add schedule: {:#}", e);
+            format!(
+                "定期投稿の追加に失敗しました :Hyperblob: ({:#}, cron 式は {} の時刻)",
+                e,
+                scheduler.tz()
+            )
+        }
+    };
+    Ok(Some(res_msg))
+}
+
+async fn schedule_list(ctx: CommandContext) -> Reply {
+    let res_msg = match get_channel_schedules(POOL.get().unwrap(), &ctx.message.channel_id).await {
+        Ok(schedules) if schedules.is_empty() => {
+            "このチャンネルには定期投稿の予定がありません".to_string()
+        }
+        Ok(schedules) => {
+            let lines = schedules
+                .iter()
+                .map(|s| format!("- {}", describe_schedule(&ctx.resource.scheduler, s)))
+                .collect::<Vec<_>>();
+            format!("このチャンネルの定期投稿:\n{}", lines.join("\n"))
+        }
+        Err(e) => {
+            error!("Failed to get schedules: {:#}", e);
+            "定期投稿の取得に失敗しました :Hyperblob:".to_string()
+        }
+    };
+    Ok(Some(res_msg))
+}
+
+async fn schedule_remove(ctx: CommandContext) -> Reply {
+    let Ok(id) = ctx.arg(0).unwrap().parse::<i64>() else {
+        return Err(UsageError("不正な ID です".to_string()));
+    };
+    let res = ctx
+        .resource
+        .scheduler
+        .remove(POOL.get().unwrap(), &ctx.message.channel_id, id)
+        .await;
+    let res_msg = match res {
+        Ok(true) => format!("定期投稿 {} を削除しました :blob_pyon:", id),
+        Ok(false) => format!(
+            "このチャンネルに ID {} の定期投稿はありません :Hyperblob:",
+            id
+        ),
+        Err(e) => {
+            error!("Failed to remove schedule: {:#}", e);
+            "定期投稿の削除に失敗しました :Hyperblob:".to_string()
+        }
+    };
+    Ok(Some(res_msg))
+}
+
+/// 引数がなければ現在の設定を表示する
+async fn quiet(ctx: CommandContext) -> Reply {
+    let pool = POOL.get().unwrap();
+    let channel_id = &ctx.message.channel_id;
+    let tz = ctx.resource.scheduler.tz();
+    let res_msg = match ctx.arg(0) {
+        None => match get_quiet_hours_with_cache(pool, channel_id).await {
+            Ok(Some(quiet)) => {
+                format!("このチャンネルでは {} ({}) の間は投稿を控えます", quiet, tz)
+            }
+            Ok(None) => "このチャンネルには投稿を控える時間帯が設定されていません".to_string(),
+            Err(e) => {
+                error!("Failed to get quiet hours: {:#}", e);
+                "時間帯の取得に失敗しました :Hyperblob:".to_string()
+            }
+        },
+        Some("off" | "no") => match set_quiet_hours(pool, channel_id, None).await {
+            Ok(()) => "投稿を控える時間帯を解除しました :blob_pyon:".to_string(),
+            Err(e) => {
+                error!("Failed to delete quiet hours: {:#}", e);
+                "時間帯の更新に失敗しました :Hyperblob:".to_string()
+            }
+        },
+        Some(x) => {
+            let quiet = x
+                .parse::<QuietHours>()
+                .map_err(|e| UsageError(format!("不正な時間帯です ({:#})", e)))?;
+            match set_quiet_hours(pool, channel_id, Some(quiet)).await {
+                Ok(()) => format!(
+                    "{} ({}) の間は投稿を控えるように設定しました :blob_pyon:",
+                    quiet, tz
+                ),
+                Err(e) => {
+                    error!("Failed to update quiet hours: {:#}", e);
+                    "時間帯の更新に失敗しました :Hyperblob:".to_string()
+                }
+            }
+        }
+    };
+    Ok(Some(res_msg))
+}
+
+/// チャンネルごとの設定と、返信の制限をまとめて表示する
+async fn settings(ctx: CommandContext) -> Reply {
+    let pool = POOL.get().unwrap();
+    let channel_id = &ctx.message.channel_id;
+    let scheduler = &ctx.resource.scheduler;
+    let config = config();
+    let mut lines = vec!["このチャンネルの設定:".to_string()];
+
+    let freq = describe_freq(channel_id).await.unwrap_or_else(|e| {
+        error!("Failed to get frequency: {:#}", e);
+        "取得に失敗しました :Hyperblob:".to_string()
+    });
+    lines.push(format!("- 返信頻度: {}", freq));
+
+    let quiet = current_quiet(ctx.clone())
+        .await
+        .unwrap_or_else(|| "取得に失敗しました :Hyperblob:".to_string());
+    lines.push(format!("- 投稿を控える時間帯: {}", quiet));
+
+    match get_channel_schedules(pool, channel_id).await {
+        Ok(schedules) => {
+            lines.push(format!(
+                "- 定期投稿: {} 件 (cron 式は {} の時刻)",
+                schedules.len(),
+                scheduler.tz()
+            ));
+            for schedule in &schedules {
+                lines.push(format!("    - {}", describe_schedule(scheduler, schedule)));
+            }
+        }
+        Err(e) => {
+            error!("Failed to get schedules: {:#}", e);
+            lines.push("- 定期投稿: 取得に失敗しました :Hyperblob:".to_string());
+        }
+    }
+    if *channel_id == config.cron_channel_id {
+        lines.push(format!(
+            "- 設定ファイルの定期投稿: `{}` (最大 {} 分遅れ, {})",
+            config.schedule.post,
+            CONFIG_POST_JITTER_MINUTES,
+            format_next_schedule(scheduler, &config.schedule.post)
+        ));
+    }
+
+    lines.push(format!(
+        "- 返信の制限: {} 秒に {} 回まで (全チャンネル共通)",
+        config.rate_limit.interval_secs, config.rate_limit.max_count
+    ));
+    Ok(Some(lines.join("\n")))
+}
+
+async fn reload(ctx: CommandContext) -> Reply {
+    let res_msg = match reload_config(POOL.get().unwrap(), &ctx.resource.api).await {
+        Ok(restart_required) if restart_required.is_empty() => {
+            "設定を再読み込みしました :blob_pyon:".to_string()
+        }
+        Ok(restart_required) => format!(
+            "設定を再読み込みしました :blob_pyon: ({} の変更は再起動後に反映されます)",
+            restart_required.join(", ")
+        ),
+        Err(e) => {
+            error!("Failed to reload config: {:#}", e);
+            format!(
+                "設定の再読み込みに失敗しました :Hyperblob:\n```\n{:#}\n```",
+                e
+            )
+        }
+    };
+    Ok(Some(res_msg))
+}
+
+/// メンション (`@someone`) で指定されたユーザーの UUID を返す
+fn mentioned_user_id(ctx: &CommandContext, arg: &str) -> Result<String, UsageError> {
+    ctx.message
+        .embedded
+        .iter()
+        .find(|e| e.type_ == "user" && e.raw == arg)
+        .map(|e| e.id.clone())
+        .ok_or_else(|| UsageError("ユーザーはメンションで指定してください".to_string()))
+}
+
+/// 指定された人格で返信する (返信は rate limit に従って投稿する)
+async fn reply_as(ctx: CommandContext) -> Reply {
+    let user_id = mentioned_user_id(&ctx, ctx.arg(0).unwrap())?;
+    if !config().target_user_ids.contains(&user_id) {
+        return Ok(Some("その人にはなれません :Hyperblob:".to_string()));
+    }
+    let text = ctx.arg(1).unwrap_or_default();
+    let Some(res_message) = generate_reply(&user_id, Purpose::Mention, text).await else {
+        info!("Failed to generate a message");
+        return Ok(None);
+    };
+    let res = ctx
+        .resource
+        .api
+        .post_message(
+            ctx.message.channel_id,
+            res_message,
+            Some(&ctx.resource.rate_limiter),
+        )
+        .await;
+    if let Err(e) = res {
+        error!("Failed to post message: {}", e);
+    }
+    Ok(None)
+}
+
+/// (ユーザーの UUID, 権限, 権限を付与する channel_id) を返す
+fn role_args(ctx: &CommandContext) -> Result<(String, Role, String), UsageError> {
+    let user_id = mentioned_user_id(ctx, ctx.arg(0).unwrap())?;
+    let role = match ctx.arg(1).unwrap().parse::<Role>() {
+        Ok(Role::Everyone) | Err(_) => {
+            return Err(UsageError(
+                "権限は owner か admin を指定してください".to_string(),
+            ))
+        }
+        Ok(role) => role,
+    };
+    let channel_id = if role.is_global() {
+        ALL_CHANNELS.to_string()
+    } else {
+        ctx.message.channel_id.clone()
+    };
+    Ok((user_id, role, channel_id))
+}
+
+async fn role_list(ctx: CommandContext) -> Reply {
+    let roles = match get_channel_roles(POOL.get().unwrap(), &ctx.message.channel_id).await {
+        Ok(roles) => roles,
+        Err(e) => {
+            error!("Failed to get roles: {:#}", e);
+            return Ok(Some("権限の取得に失敗しました :Hyperblob:".to_string()));
+        }
+    };
+    let mut lines = vec!["このチャンネルで権限を持つユーザー:".to_string()];
+    for user_id in &config().admin.user_ids {
+        lines.push(format!(
+            "- `{}`: {} (設定ファイル)",
+            user_id,
+            Role::Owner.display_name()
+        ));
+    }
+    for record in &roles {
+        let role = record
+            .role
+            .parse::<Role>()
+            .map_or(record.role.as_str(), |r| r.display_name());
+        lines.push(format!("- `{}`: {}", record.user_id, role));
+    }
+    if lines.len() == 1 {
+        lines.push("- (なし)".to_string());
+    }
+    Ok(Some(lines.join("\n")))
+}
+
+async fn role_grant(ctx: CommandContext) -> Reply {
+    let (user_id, role, channel_id) = role_args(&ctx)?;
+    let res = grant_role(POOL.get().unwrap(), &user_id, &channel_id, role.as_str()).await;
+    let res_msg = match res {
+        Ok(()) => {
+            info!(
+                "{} granted {} to {} in {:?}",
+                ctx.message.user.name, role, user_id, channel_id
+            );
+            format!(
+                "{} に{}の権限を付与しました :blob_pyon:",
+                ctx.arg(0).unwrap(),
+                role.display_name()
+            )
+        }
+        Err(e) => {
+            error!("Failed to grant role: {:#}", e);
+            "権限の付与に失敗しました :Hyperblob:".to_string()
+        }
+    };
+    Ok(Some(res_msg))
+}
+
+async fn role_revoke(ctx: CommandContext) -> Reply {
+    let (user_id, role, channel_id) = role_args(&ctx)?;
+    let res = revoke_role(POOL.get().unwrap(), &user_id, &channel_id, role.as_str()).await;
+    let res_msg = match res {
+        Ok(true) => {
+            info!(
+                "{} revoked {} from {} in {:?}",
+                ctx.message.user.name, role, user_id, channel_id
+            );
+            let mut res_msg = format!(
+                "{} の{}の権限を剥奪しました :blob_pyon:",
+                ctx.arg(0).unwrap(),
+                role.display_name()
+            );
+            if config().is_owner(&user_id) {
+                res_msg.push_str(" (設定ファイルで指定されているので、オーナーのままです)");
+            }
+            res_msg
+        }
+        Ok(false) => format!(
+            "{} は{}の権限を付与されていません :Hyperblob:",
+            ctx.arg(0).unwrap(),
+            role.display_name()
+        ),
+        Err(e) => {
+            error!("Failed to revoke role: {:#}", e);
+            "権限の剥奪に失敗しました :Hyperblob:".to_string()
+        }
+    };
+    Ok(Some(res_msg))
+}
@@ -0,0 +1,733 @@
+//! メンションで使うコマンドの解釈と実行
+//!
+//! `@BOT_SSlime /schedule add "0 0 9 * * *" 30` のように、メンションの直後に
+//! `/` (または `\`) で始まるコマンド名を書く
+//! 引数は空白区切りで、空白を含む引数は `"` で囲む
+mod builtin;
+
+use std::{future::Future, pin::Pin};
+
+use anyhow::{bail, ensure};
+use log::{error, warn};
+use traq_ws_bot::events::common::Message;
+
+pub use builtin::COMMANDS;
+
+use crate::{
+    role::{role_of, Role},
+    Resource, POOL,
+};
+
+/// コマンドの実行結果 (投稿する返信)
+pub type Reply = Result<Option<String>, UsageError>;
+
+/// コマンドの処理
+pub type Handler = fn(CommandContext) -> Pin<Box<dyn Future<Output = Reply> + Send>>;
+
+/// コマンドに関係する現在の値 (`/help` で表示する)
+pub type Current = fn(CommandContext) -> Pin<Box<dyn Future<Output = Option<String>> + Send>>;
+
+/// 引数が不正なときのエラー (使い方と一緒に返信する)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsageError(pub String);
+
+/// コマンドを実行するときに渡すもの
+#[derive(Clone)]
+pub struct CommandContext {
+    pub resource: Resource,
+    pub message: Message,
+    /// DM で実行されたかどうか
+    pub direct_message: bool,
+    /// サブコマンドより後の引数
+    pub args: Vec<String>,
+}
+
+impl CommandContext {
+    pub fn arg(&self, i: usize) -> Option<&str> {
+        self.args.get(i).map(String::as_str)
+    }
+}
+
+/// コマンドの引数の定義
+#[derive(Debug, Clone, Copy)]
+pub struct Arg {
+    pub name: &'static str,
+    pub required: bool,
+    /// 残りのテキストを空白や `"` も含めてそのまま 1 つの引数にする (最後の引数のみ)
+    pub rest: bool,
+}
+
+impl Arg {
+    pub const fn required(name: &'static str) -> Self {
+        Self {
+            name,
+            required: true,
+            rest: false,
+        }
+    }
+
+    pub const fn optional(name: &'static str) -> Self {
+        Self {
+            name,
+            required: false,
+            rest: false,
+        }
+    }
+
+    /// 省略できる、残りのテキストすべての引数
+    pub const fn rest(name: &'static str) -> Self {
+        Self {
+            name,
+            required: false,
+            rest: true,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Action {
+    Run(Handler),
+    Subcommands(Vec<Command>),
+}
+
+/// コマンドの定義
+#[derive(Clone)]
+pub struct Command {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub description: &'static str,
+    /// `/` を付けずに、引数なしで書いても実行する (`@BOT_SSlime join` など)
+    pub bare: bool,
+    /// DM でも実行できる
+    pub direct_message: bool,
+    /// 実行するのに必要な権限
+    pub role: Role,
+    /// 引数なしでは現在の値を表示するだけなので、権限にかかわらず実行できる
+    pub query_without_args: bool,
+    pub args: Vec<Arg>,
+    pub current: Option<Current>,
+    action: Action,
+}
+
+impl Command {
+    pub fn new(name: &'static str, description: &'static str, handler: Handler) -> Self {
+        Self {
+            name,
+            aliases: &[],
+            description,
+            bare: false,
+            direct_message: false,
+            role: Role::Everyone,
+            query_without_args: false,
+            args: Vec::new(),
+            current: None,
+            action: Action::Run(handler),
+        }
+    }
+
+    /// サブコマンドを持つコマンド
+    pub fn group(name: &'static str, description: &'static str, subcommands: Vec<Command>) -> Self {
+        Self {
+            name,
+            aliases: &[],
+            description,
+            bare: false,
+            direct_message: false,
+            role: Role::Everyone,
+            query_without_args: false,
+            args: Vec::new(),
+            current: None,
+            action: Action::Subcommands(subcommands),
+        }
+    }
+
+    pub fn aliases(mut self, aliases: &'static [&'static str]) -> Self {
+        self.aliases = aliases;
+        self
+    }
+
+    pub fn bare(mut self) -> Self {
+        self.bare = true;
+        self
+    }
+
+    pub fn in_direct_message(mut self) -> Self {
+        self.direct_message = true;
+        self
+    }
+
+    pub fn role(mut self, role: Role) -> Self {
+        self.role = role;
+        self
+    }
+
+    pub fn query_without_args(mut self) -> Self {
+        self.query_without_args = true;
+        self
+    }
+
+    pub fn args(mut self, args: Vec<Arg>) -> Self {
+        self.args = args;
+        self
+    }
+
+    pub fn current(mut self, current: Current) -> Self {
+        self.current = Some(current);
+        self
+    }
+
+    pub fn is_named(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+            || self.aliases.iter().any(|a| a.eq_ignore_ascii_case(name))
+    }
+
+    /// 引数の数が正しければ true を返す
+    fn accepts(&self, count: usize) -> bool {
+        let required = self.args.iter().filter(|a| a.required).count();
+        (required..=self.args.len()).contains(&count)
+    }
+}
+
+/// path (コマンドとサブコマンドの列) の書き方を返す
+///
+/// 例: `/schedule add <cron 式> [最大の遅れ (分)]`
+pub fn syntax(path: &[&Command]) -> String {
+    let mut words = path.iter().map(|c| c.name).collect::<Vec<_>>().join(" ");
+    words.insert(0, '/');
+    if let Some(last) = path.last() {
+        for arg in &last.args {
+            if arg.required {
+                words.push_str(&format!(" <{}>", arg.name));
+            } else {
+                words.push_str(&format!(" [{}]", arg.name));
+            }
+        }
+    }
+    words
+}
+
+/// path から実行できるすべてのコマンドの path を返す (サブコマンドがあればそれぞれの path)
+pub fn runnable_paths<'a>(path: &[&'a Command]) -> Vec<Vec<&'a Command>> {
+    let Some(last) = path.last() else {
+        return Vec::new();
+    };
+    match &last.action {
+        Action::Run(_) => vec![path.to_vec()],
+        Action::Subcommands(subcommands) => subcommands
+            .iter()
+            .flat_map(|sub| {
+                let mut path = path.to_vec();
+                path.push(sub);
+                runnable_paths(&path)
+            })
+            .collect(),
+    }
+}
+
+/// path の書き方と説明と、必要な権限を 1 行で表示する
+pub fn describe(path: &[&Command]) -> String {
+    let Some(last) = path.last() else {
+        return String::new();
+    };
+    let mut line = format!("- `{}`: {}", syntax(path), last.description);
+    let role = path.iter().map(|c| c.role).max().unwrap_or(Role::Everyone);
+    if role > Role::Everyone {
+        let action = if last.query_without_args {
+            "変更は"
+        } else {
+            ""
+        };
+        line.push_str(&format!(" ({}{}以上)", action, role.display_name()));
+    }
+    line
+}
+
+/// text の先頭の空白区切りの引数と、その後の残りのテキストを返す (引数がなければ None)
+///
+/// `"` で囲んだ部分は空白を含めて 1 つの引数になり、その中では `\"` で `"` を書ける
+fn next_token(text: &str) -> anyhow::Result<Option<(String, &str)>> {
+    let text = text.trim_start();
+    let mut chars = text.char_indices().peekable();
+    let Some(&(_, first)) = chars.peek() else {
+        return Ok(None);
+    };
+    let mut token = String::new();
+    if first == '"' {
+        chars.next();
+        loop {
+            match chars.next() {
+                Some((_, '"')) => break,
+                Some((_, '\\')) if chars.peek().is_some_and(|&(_, c)| c == '"') => {
+                    token.push('"');
+                    chars.next();
+                }
+                Some((_, c)) => token.push(c),
+                None => bail!("`\"` が閉じられていません"),
+            }
+        }
+        ensure!(
+            chars.peek().is_none_or(|&(_, c)| c.is_whitespace()),
+            "`\"` の後には空白が必要です"
+        );
+    } else {
+        while let Some((_, c)) = chars.next_if(|&(_, c)| !c.is_whitespace()) {
+            token.push(c);
+        }
+    }
+    let rest = chars.peek().map_or("", |&(i, _)| &text[i..]);
+    Ok(Some((token, rest)))
+}
+
+/// text を command の引数に分ける
+fn parse_args(command: &Command, mut text: &str) -> anyhow::Result<Vec<String>> {
+    let mut args = Vec::new();
+    for arg in &command.args {
+        if arg.rest {
+            let rest = text.trim();
+            if !rest.is_empty() {
+                args.push(rest.to_string());
+            }
+            text = "";
+            break;
+        }
+        let Some((token, rest)) = next_token(text)? else {
+            break;
+        };
+        args.push(token);
+        text = rest;
+    }
+    ensure!(
+        next_token(text)?.is_none() && command.accepts(args.len()),
+        "引数の数が正しくありません"
+    );
+    Ok(args)
+}
+
+/// メッセージを解釈した結果
+pub enum Parsed<'a> {
+    /// コマンドではない
+    NotCommand,
+    /// path (コマンドとサブコマンドの列) を args で実行する
+    Command {
+        path: Vec<&'a Command>,
+        args: Vec<String>,
+    },
+    /// コマンドだが、書き方が正しくない
+    Usage {
+        path: Vec<&'a Command>,
+        error: String,
+    },
+}
+
+/// text (先頭のメンションは無視する) を commands のコマンドとして解釈する
+///
+/// direct_message が true なら、DM で実行できるコマンドだけを対象にする
+pub fn parse<'a>(commands: &'a [Command], text: &str, direct_message: bool) -> Parsed<'a> {
+    let mut rest = text.trim_start();
+    if rest.starts_with('@') {
+        rest = rest
+            .split_once(char::is_whitespace)
+            .map_or("", |(_, rest)| rest.trim_start());
+    }
+    let (head, rest) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    let (name, slash) = match head.strip_prefix(['/', '\\']) {
+        Some(name) => (name, true),
+        None => (head, false),
+    };
+    let Some(command) = commands
+        .iter()
+        .filter(|c| c.direct_message || !direct_message)
+        .find(|c| c.is_named(name))
+    else {
+        return Parsed::NotCommand;
+    };
+    if !(slash || command.bare && rest.trim().is_empty()) {
+        return Parsed::NotCommand;
+    }
+
+    let mut path = vec![command];
+    let mut command = command;
+    let mut rest = rest;
+    while let Action::Subcommands(subcommands) = &command.action {
+        let name = match next_token(rest) {
+            Ok(Some((name, r))) => {
+                rest = r;
+                name
+            }
+            Ok(None) => {
+                return Parsed::Usage {
+                    path,
+                    error: "サブコマンドを指定してください".to_string(),
+                }
+            }
+            Err(e) => {
+                return Parsed::Usage {
+                    path,
+                    error: e.to_string(),
+                }
+            }
+        };
+        let Some(sub) = subcommands.iter().find(|c| c.is_named(&name)) else {
+            return Parsed::Usage {
+                path,
+                error: format!("不明なサブコマンドです: {}", name),
+            };
+        };
+        path.push(sub);
+        command = sub;
+    }
+    match parse_args(command, rest) {
+        Ok(args) => Parsed::Command { path, args },
+        Err(e) => Parsed::Usage {
+            path,
+            error: e.to_string(),
+        },
+    }
+}
+
+/// path を args で実行するのに必要な権限 (サブコマンドの権限も含めて最も強いもの)
+pub fn required_role(path: &[&Command], args: &[String]) -> Role {
+    let Some(last) = path.last() else {
+        return Role::Everyone;
+    };
+    if last.query_without_args && args.is_empty() {
+        return Role::Everyone;
+    }
+    path.iter().map(|c| c.role).max().unwrap_or(Role::Everyone)
+}
+
+/// message の投稿者が required 以上の権限を持っていなければ、返信する理由を返す
+///
+/// DM でも、投稿者の権限は付与されたものだけで判定する
+/// (誰でも BOT と DM できるので、DM のチャンネルの管理者として扱うと誰でも定期投稿などを追加できてしまう)
+async fn check_role(path: &[&Command], required: Role, message: &Message) -> Option<String> {
+    if required == Role::Everyone {
+        return None;
+    }
+    let role = match role_of(POOL.get().unwrap(), &message.user.id, &message.channel_id).await {
+        Ok(role) => role,
+        Err(e) => {
+            error!("Failed to get role: {:#}", e);
+            return Some("権限の確認に失敗しました :Hyperblob:".to_string());
+        }
+    };
+    if role >= required {
+        return None;
+    }
+    warn!(
+        "{} ({}) tried to run `{}` in {} without permission (requires {}, has {})",
+        message.user.name,
+        message.user.id,
+        syntax(path),
+        message.channel_id,
+        required,
+        role
+    );
+    Some(format!(
+        "権限がありません :Hyperblob: ({}以上が必要です)",
+        required.display_name()
+    ))
+}
+
+/// 使い方を添えたエラーの返信
+fn usage_message(path: &[&Command], error: &str) -> String {
+    let usages = runnable_paths(path)
+        .iter()
+        .map(|path| describe(path))
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!("{} :Hyperblob:\n使い方:\n{}", error, usages)
+}
+
+/// message がコマンドなら実行して返信し、true を返す
+pub async fn dispatch(
+    commands: &[Command],
+    resource: &Resource,
+    message: &Message,
+    direct_message: bool,
+) -> bool {
+    let reply = match parse(commands, &message.plain_text, direct_message) {
+        Parsed::NotCommand => return false,
+        Parsed::Usage { path, error } => Some(usage_message(&path, &error)),
+        Parsed::Command { path, args } => {
+            let Action::Run(handler) = &path.last().unwrap().action else {
+                unreachable!("subcommands are resolved by parse");
+            };
+            let required = required_role(&path, &args);
+            match check_role(&path, required, message).await {
+                Some(denied) => Some(denied),
+                None => {
+                    let ctx = CommandContext {
+                        resource: resource.clone(),
+                        message: message.clone(),
+                        direct_message,
+                        args,
+                    };
+                    match handler(ctx).await {
+                        Ok(reply) => reply,
+                        Err(UsageError(error)) => Some(usage_message(&path, &error)),
+                    }
+                }
+            }
+        }
+    };
+    if let Some(reply) = reply {
+        let res = resource
+            .api
+            .post_message(message.channel_id.clone(), reply, None)
+            .await;
+        if let Err(e) = res {
+            error!("Failed to post message: {}", e);
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noop(_: CommandContext) -> Pin<Box<dyn Future<Output = Reply> + Send>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    fn commands() -> Vec<Command> {
+        vec![
+            Command::new("join", "参加する", noop).bare(),
+            Command::new("help", "ヘルプ", noop).in_direct_message(),
+            Command::new("freq", "頻度を変更する", noop)
+                .aliases(&["frequency"])
+                .args(vec![Arg::required("頻度")]),
+            Command::group(
+                "schedule",
+                "定期投稿",
+                vec![
+                    Command::new("add", "追加する", noop)
+                        .args(vec![Arg::required("cron 式"), Arg::optional("最大の遅れ")]),
+                    Command::new("rm", "削除する", noop)
+                        .aliases(&["remove"])
+                        .args(vec![Arg::required("ID")]),
+                ],
+            ),
+            Command::new("as", "人格を指定する", noop)
+                .args(vec![Arg::required("@ユーザー"), Arg::rest("メッセージ")]),
+        ]
+    }
+
+    fn tokenize(mut text: &str) -> anyhow::Result<Vec<String>> {
+        let mut tokens = Vec::new();
+        while let Some((token, rest)) = next_token(text)? {
+            tokens.push(token);
+            text = rest;
+        }
+        Ok(tokens)
+    }
+
+    fn parsed(
+        commands: &[Command],
+        text: &str,
+    ) -> Result<(Vec<&'static str>, Vec<String>), String> {
+        match parse(commands, text, false) {
+            Parsed::NotCommand => Err("not a command".to_string()),
+            Parsed::Command { path, args } => Ok((path.iter().map(|c| c.name).collect(), args)),
+            Parsed::Usage { error, .. } => Err(error),
+        }
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(tokenize("  a  b\tc ").unwrap(), vec!["a", "b", "c"]);
+        assert_eq!(
+            tokenize(r#"add "0 0 9 * * *" 30"#).unwrap(),
+            vec!["add", "0 0 9 * * *", "30"]
+        );
+        assert_eq!(tokenize(r#""a \"b\"" """#).unwrap(), vec![r#"a "b""#, ""]);
+        assert!(tokenize(r#"add "0 0 9"#).is_err());
+        assert!(tokenize(r#""a"b"#).is_err());
+        assert!(tokenize("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse() {
+        let commands = commands();
+        assert_eq!(
+            parsed(&commands, "@BOT_SSlime /freq 50"),
+            Ok((vec!["freq"], vec!["50".to_string()]))
+        );
+        assert_eq!(
+            parsed(&commands, r"@BOT_SSlime \FREQUENCY 50"),
+            Ok((vec!["freq"], vec!["50".to_string()]))
+        );
+        assert_eq!(
+            parsed(&commands, r#"@BOT_SSlime /schedule add "0 0 9 * * *""#),
+            Ok((vec!["schedule", "add"], vec!["0 0 9 * * *".to_string()]))
+        );
+        assert_eq!(
+            parsed(&commands, "@BOT_SSlime /schedule remove 3"),
+            Ok((vec!["schedule", "rm"], vec!["3".to_string()]))
+        );
+        assert_eq!(parsed(&commands, "/join"), Ok((vec!["join"], vec![])));
+    }
+
+    #[test]
+    fn test_parse_rest() {
+        let commands = commands();
+        assert_eq!(
+            parsed(&commands, r#"@BOT_SSlime /as @SSlime  今日は "いい 天気""#),
+            Ok((
+                vec!["as"],
+                vec!["@SSlime".to_string(), r#"今日は "いい 天気""#.to_string()]
+            ))
+        );
+        assert_eq!(
+            parsed(&commands, "@BOT_SSlime /as @SSlime "),
+            Ok((vec!["as"], vec!["@SSlime".to_string()]))
+        );
+        assert!(parsed(&commands, "@BOT_SSlime /as").is_err());
+        assert_eq!(syntax(&[&commands[4]]), "/as <@ユーザー> [メッセージ]");
+    }
+
+    #[test]
+    fn test_parse_bare() {
+        let commands = commands();
+        assert_eq!(
+            parsed(&commands, "@BOT_SSlime join"),
+            Ok((vec!["join"], vec![]))
+        );
+        // 文中の単語はコマンドとして扱わない
+        assert!(matches!(
+            parse(&commands, "@BOT_SSlime join してほしい", false),
+            Parsed::NotCommand
+        ));
+        assert!(matches!(
+            parse(&commands, "@BOT_SSlime rejoin", false),
+            Parsed::NotCommand
+        ));
+        assert!(matches!(
+            parse(&commands, "@BOT_SSlime freq 50", false),
+            Parsed::NotCommand
+        ));
+        assert!(matches!(
+            parse(&commands, "@BOT_SSlime /unknown", false),
+            Parsed::NotCommand
+        ));
+    }
+
+    #[test]
+    fn test_parse_direct_message() {
+        let commands = commands();
+        assert!(matches!(
+            parse(&commands, "/join", true),
+            Parsed::NotCommand
+        ));
+        assert!(matches!(
+            parse(&commands, "/help", true),
+            Parsed::Command { .. }
+        ));
+    }
+
+    #[test]
+    fn test_parse_usage_error() {
+        let commands = commands();
+        assert!(parsed(&commands, "@BOT_SSlime /freq").is_err());
+        assert!(parsed(&commands, "@BOT_SSlime /freq 1 2").is_err());
+        assert!(parsed(&commands, "@BOT_SSlime /schedule").is_err());
+        assert!(parsed(&commands, "@BOT_SSlime /schedule edit 1").is_err());
+        assert!(parsed(&commands, r#"@BOT_SSlime /schedule add "0 0"#).is_err());
+    }
+
+    #[test]
+    fn test_builtin_names_are_unique() {
+        fn check(commands: &[Command]) {
+            let mut names = commands
+                .iter()
+                .flat_map(|c| std::iter::once(c.name).chain(c.aliases.iter().copied()))
+                .collect::<Vec<_>>();
+            let count = names.len();
+            names.sort_unstable();
+            names.dedup();
+            assert_eq!(names.len(), count);
+            for command in commands {
+                if let Action::Subcommands(subcommands) = &command.action {
+                    check(subcommands);
+                }
+            }
+        }
+        check(&COMMANDS);
+    }
+
+    #[test]
+    fn test_builtin_usages() {
+        // /help はこの一覧を表示する
+        let usages = COMMANDS
+            .iter()
+            .flat_map(|c| runnable_paths(&[c]))
+            .map(|path| syntax(&path))
+            .collect::<Vec<_>>();
+        assert!(usages.contains(&"/as <@ユーザー> [メッセージ]".to_string()));
+        assert!(usages.contains(&"/schedule rm <ID>".to_string()));
+    }
+
+    #[test]
+    fn test_required_role() {
+        let commands = vec![
+            Command::new("freq", "頻度", noop)
+                .role(Role::ChannelAdmin)
+                .query_without_args()
+                .args(vec![Arg::optional("頻度")]),
+            Command::group(
+                "role",
+                "権限",
+                vec![
+                    Command::new("list", "一覧", noop),
+                    Command::new("grant", "付与", noop).role(Role::Owner),
+                ],
+            ),
+        ];
+        let role = |text: &str| match parse(&commands, text, false) {
+            Parsed::Command { path, args } => required_role(&path, &args),
+            _ => panic!("not a command: {}", text),
+        };
+        assert_eq!(role("/freq"), Role::Everyone);
+        assert_eq!(role("/freq 100"), Role::ChannelAdmin);
+        assert_eq!(role("/role list"), Role::Everyone);
+        assert_eq!(role("/role grant"), Role::Owner);
+    }
+
+    #[test]
+    fn test_required_role_in_direct_message() {
+        let role = |text: &str| match parse(&COMMANDS, text, true) {
+            Parsed::Command { path, args } => required_role(&path, &args),
+            _ => panic!("not a command: {}", text),
+        };
+        // 権限を付与されていないユーザー (Everyone) は、DM でも定期投稿を変更できない
+        assert_eq!(role(r#"/schedule add "0 0 * * * *" 0"#), Role::ChannelAdmin);
+        assert_eq!(role("/schedule rm 1"), Role::ChannelAdmin);
+        assert_eq!(role("/schedule remove 1"), Role::ChannelAdmin);
+        assert_eq!(role("/schedule list"), Role::Everyone);
+        assert_eq!(role("/help"), Role::Everyone);
+    }
+
+    #[test]
+    fn test_usages() {
+        let commands = commands();
+        assert_eq!(syntax(&[&commands[2]]), "/freq <頻度>");
+        let usages = runnable_paths(&[&commands[3]])
+            .iter()
+            .map(|path| syntax(path))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            usages,
+            vec!["/schedule add <cron 式> [最大の遅れ]", "/schedule rm <ID>"]
+        );
+
+        let freq = Command::new("freq", "頻度を変更する", noop)
+            .role(Role::ChannelAdmin)
+            .query_without_args()
+            .args(vec![Arg::optional("頻度")]);
+        assert_eq!(
+            describe(&[&freq]),
+            "- `/freq [頻度]`: 頻度を変更する (変更はチャンネル管理者以上)"
+        );
+    }
+}
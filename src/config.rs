@@ -0,0 +1,697 @@
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{anyhow, bail, ensure, Context as _};
+use chrono_tz::Tz;
+use dotenv::dotenv;
+use regex::RegexSet;
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+
+use crate::{
+    constraints::{Constraints, ConstraintsOverride, Purpose},
+    cron::CronSchedule,
+};
+
+/// 設定ファイルのパスを指定する環境変数
+const CONFIG_PATH_ENV: &str = "CONFIG_PATH";
+
+/// 環境変数で指定されていないときの設定ファイルのパス
+const DEFAULT_CONFIG_PATH: &str = "config.yaml";
+
+static CONFIG: RwLock<Option<Arc<Config>>> = RwLock::new(None);
+
+/// 現在の設定を返す
+///
+/// 再読み込みされても、返された設定は変わらない
+pub fn config() -> Arc<Config> {
+    CONFIG
+        .read()
+        .unwrap()
+        .clone()
+        .expect("config is not loaded")
+}
+
+/// 設定を読み込んで検証し、以降 config() から参照できるようにする
+pub fn init() -> anyhow::Result<()> {
+    dotenv().ok();
+    let config = Config::load()?;
+    let mut current = CONFIG.write().unwrap();
+    if current.is_some() {
+        bail!("config is already loaded");
+    }
+    *current = Some(Arc::new(config));
+    Ok(())
+}
+
+/// 設定を読み込み直して置き換え、(古い設定, 新しい設定) を返す
+///
+/// 検証に失敗した場合は、現在の設定をそのまま使い続ける
+pub fn reload() -> anyhow::Result<(Arc<Config>, Arc<Config>)> {
+    let new = Arc::new(Config::load()?);
+    let old = CONFIG
+        .write()
+        .unwrap()
+        .replace(new.clone())
+        .ok_or_else(|| anyhow!("config is not loaded"))?;
+    Ok((old, new))
+}
+
+/// 再読み込みした設定の反映に失敗したときに、reload() の前の設定に戻す
+pub fn restore(old: Arc<Config>) {
+    *CONFIG.write().unwrap() = Some(old);
+}
+
+/// 環境変数の値の解釈の仕方
+#[derive(Debug, Clone, Copy)]
+enum EnvKind {
+    String,
+    /// カンマ区切りの文字列のリスト
+    List,
+    Number,
+    /// `1` / `0` または `true` / `false`
+    Bool,
+}
+
+/// 設定ファイルの値を上書きする環境変数と、上書きする値のパス
+const ENV_OVERRIDES: &[(&str, &[&str], EnvKind)] = &[
+    ("BOT_ID", &["bot_id"], EnvKind::String),
+    ("BOT_USER_ID", &["bot_user_id"], EnvKind::String),
+    ("TARGET_USER_IDS", &["target_user_ids"], EnvKind::List),
+    ("CRON_CHANNEL_ID", &["cron_channel_id"], EnvKind::String),
+    ("BASE_URL", &["base_url"], EnvKind::String),
+    ("TIMEZONE", &["timezone"], EnvKind::String),
+    ("DEFAULT_FREQ", &["default_freq"], EnvKind::Number),
+    ("POST_SCHEDULE", &["schedule", "post"], EnvKind::String),
+    (
+        "UPDATE_MARKOV_SCHEDULE",
+        &["schedule", "update_markov"],
+        EnvKind::String,
+    ),
+    (
+        "RECONCILE_SCHEDULE",
+        &["schedule", "reconcile"],
+        EnvKind::String,
+    ),
+    ("RECONCILE_DAYS", &["reconcile_days"], EnvKind::Number),
+    (
+        "CRAWL_REQUESTS_PER_SECOND",
+        &["crawl", "requests_per_second"],
+        EnvKind::Number,
+    ),
+    (
+        "RATE_LIMIT_MAX_COUNT",
+        &["rate_limit", "max_count"],
+        EnvKind::Number,
+    ),
+    (
+        "RATE_LIMIT_INTERVAL_SECS",
+        &["rate_limit", "interval_secs"],
+        EnvKind::Number,
+    ),
+    ("MARKOV_ORDER", &["markov", "order"], EnvKind::Number),
+    (
+        "MARKOV_MIN_ORDER",
+        &["markov", "min_order"],
+        EnvKind::Number,
+    ),
+    (
+        "MARKOV_BACKOFF_THRESHOLD",
+        &["markov", "backoff_threshold"],
+        EnvKind::Number,
+    ),
+    (
+        "NOVELTY_MIN_EDIT_DISTANCE",
+        &["novelty", "min_edit_distance"],
+        EnvKind::Number,
+    ),
+    (
+        "NOVELTY_MAX_OVERLAP_RATIO",
+        &["novelty", "max_overlap_ratio"],
+        EnvKind::Number,
+    ),
+    ("KEYWORD_REPLY", &["keyword_reply"], EnvKind::Bool),
+    ("ADMIN_USER_IDS", &["admin", "user_ids"], EnvKind::List),
+    ("ADMIN_HTTP_PORT", &["admin", "http_port"], EnvKind::Number),
+];
+
+/// 設定ファイルの内容
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    bot_id: String,
+    bot_user_id: String,
+    target_user_ids: Vec<String>,
+    cron_channel_id: String,
+    #[serde(default = "default_base_url")]
+    base_url: String,
+    #[serde(default = "default_timezone")]
+    timezone: String,
+    #[serde(default = "default_block_message_patterns")]
+    block_message_patterns: Vec<String>,
+    #[serde(default = "default_freq")]
+    default_freq: i64,
+    #[serde(default = "default_reconcile_days")]
+    reconcile_days: i64,
+    #[serde(default)]
+    crawl: CrawlConfig,
+    #[serde(default)]
+    schedule: ScheduleConfig,
+    #[serde(default)]
+    rate_limit: RateLimitConfig,
+    #[serde(default)]
+    markov: MarkovConfig,
+    #[serde(default)]
+    novelty: NoveltyConfig,
+    #[serde(default)]
+    keyword_reply: bool,
+    #[serde(default)]
+    constraints: HashMap<Purpose, ConstraintsOverride>,
+    #[serde(default)]
+    admin: AdminConfig,
+}
+
+fn default_base_url() -> String {
+    "https://q.trap.jp/api/v3".to_string()
+}
+
+fn default_timezone() -> String {
+    "Asia/Tokyo".to_string()
+}
+
+/// 省略された場合に学習しないメッセージ (:awoo: だけ、URL だけ、「う」だけ、% で始まるもの)
+fn default_block_message_patterns() -> Vec<String> {
+    [
+        r"^:awoo:$",
+        r"^(?:https?:)?//(?:\w|[.-])+/\S+\n*$",
+        r"^\[[^\[\]]+\]\((?:https?:)?//(?:\w|[.-])+/\S+\)\n*$",
+        r"^う+\n*$",
+        r"^%",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn default_freq() -> i64 {
+    20
+}
+
+fn default_reconcile_days() -> i64 {
+    7
+}
+
+/// 定期実行の cron 式 (timezone の時刻)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScheduleConfig {
+    /// 定期投稿
+    pub post: String,
+    /// markov chain の更新
+    pub update_markov: String,
+    /// 編集・削除されたメッセージの確認
+    pub reconcile: String,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self {
+            post: "0 0 0,7-23 * * *".to_string(),
+            update_markov: "0 0 9 * * *".to_string(),
+            reconcile: "0 30 9 * * *".to_string(),
+        }
+    }
+}
+
+/// メッセージの収集の設定
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CrawlConfig {
+    /// 1 秒あたりに送る検索のリクエストの上限
+    pub requests_per_second: f64,
+}
+
+impl CrawlConfig {
+    /// requests_per_second の下限 (これより小さいと、リクエストの間隔が長すぎて取得が終わらない)
+    pub const MIN_REQUESTS_PER_SECOND: f64 = 0.01;
+}
+
+impl Default for CrawlConfig {
+    fn default() -> Self {
+        Self {
+            requests_per_second: 3.0,
+        }
+    }
+}
+
+/// 返信の rate limit (interval_secs 秒間に max_count 回まで)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub max_count: usize,
+    pub interval_secs: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_count: 5,
+            interval_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MarkovConfig {
+    /// markov chain の最大の次数
+    pub order: usize,
+    /// backoff で用いる最小の次数 (指定しない場合は order と同じで backoff しない)
+    pub min_order: Option<usize>,
+    /// 文脈の出現回数がこれ未満のときは低い次数にフォールバックする
+    pub backoff_threshold: usize,
+}
+
+impl Default for MarkovConfig {
+    fn default() -> Self {
+        Self {
+            order: 2,
+            min_order: None,
+            backoff_threshold: 3,
+        }
+    }
+}
+
+impl MarkovConfig {
+    /// (min_order, max_order) を返す
+    pub fn orders(&self) -> (usize, usize) {
+        (self.min_order.unwrap_or(self.order), self.order)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NoveltyConfig {
+    /// 学習元のメッセージとの編集距離がこれ未満の生成結果は捨てる
+    pub min_edit_distance: usize,
+    /// 学習元のメッセージと共通する部分の割合がこれを超える生成結果は捨てる
+    pub max_overlap_ratio: f64,
+}
+
+impl Default for NoveltyConfig {
+    fn default() -> Self {
+        Self {
+            min_edit_distance: 1,
+            max_overlap_ratio: 1.0,
+        }
+    }
+}
+
+/// 管理用の操作 (設定の再読み込みなど) の設定
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// BOT のオーナーのユーザーの UUID (DB で権限を付与しなくても常にオーナー)
+    pub user_ids: Vec<String>,
+    /// 管理用の HTTP サーバーのポート (指定しない場合は起動しない)
+    pub http_port: Option<u16>,
+}
+
+/// 検証済みの設定
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// この BOT の UUID
+    pub bot_id: String,
+    /// この BOT の USER ID
+    pub bot_user_id: String,
+    /// 収集するユーザーの UUID (先頭のユーザーがデフォルトの人格になる)
+    pub target_user_ids: Vec<String>,
+    /// 定期投稿するチャンネルの UUID
+    pub cron_channel_id: String,
+    /// traQ API の base URL
+    pub base_url: String,
+    /// cron 式や時刻を解釈・表示するタイムゾーン
+    pub timezone: Tz,
+    /// この正規表現に一致するメッセージは、markov chain に反映されない
+    pub block_message_regex: RegexSet,
+    /// 頻度が設定されていないチャンネルでの返信頻度 (%)
+    pub default_freq: i64,
+    /// 直近この日数の間に投稿されたメッセージについて、編集・削除されていないか確認する
+    pub reconcile_days: i64,
+    pub crawl: CrawlConfig,
+    pub schedule: ScheduleConfig,
+    pub rate_limit: RateLimitConfig,
+    pub markov: MarkovConfig,
+    pub novelty: NoveltyConfig,
+    /// 返信に返信元のメッセージに含まれる単語を含めるかどうか
+    pub keyword_reply: bool,
+    /// 場面ごとのメッセージ生成の制約
+    pub constraints: HashMap<Purpose, Constraints>,
+    pub admin: AdminConfig,
+}
+
+impl Config {
+    /// 環境変数 `CONFIG_PATH` (default: `config.yaml`) の設定ファイルを読み込み、環境変数で上書きして検証する
+    pub fn load() -> anyhow::Result<Self> {
+        let (path, required) = match env::var(CONFIG_PATH_ENV) {
+            Ok(path) => (PathBuf::from(path), true),
+            Err(_) => (PathBuf::from(DEFAULT_CONFIG_PATH), false),
+        };
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => Some(content),
+            Err(e) if !required && e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+        Self::parse(content.as_deref(), |key| env::var(key).ok())
+            .with_context(|| format!("invalid config ({})", path.display()))
+    }
+
+    /// 設定ファイルの内容を getenv で得られる環境変数で上書きして検証する
+    fn parse(
+        content: Option<&str>,
+        getenv: impl Fn(&str) -> Option<String>,
+    ) -> anyhow::Result<Self> {
+        let mut value = match content {
+            Some(content) => serde_yaml::from_str(content)?,
+            None => Value::Mapping(Mapping::new()),
+        };
+        if value.is_null() {
+            value = Value::Mapping(Mapping::new());
+        }
+        for (key, path, kind) in ENV_OVERRIDES {
+            if let Some(v) = getenv(key) {
+                let v = parse_env(&v, *kind).with_context(|| format!("invalid {}", key))?;
+                set_path(&mut value, path, v)
+                    .with_context(|| format!("failed to override with {}", key))?;
+            }
+        }
+        let file: ConfigFile = serde_yaml::from_value(value)?;
+
+        ensure!(
+            !file.target_user_ids.is_empty(),
+            "target_user_ids must not be empty"
+        );
+        reqwest::Url::parse(&file.base_url)
+            .with_context(|| format!("invalid base_url: {}", file.base_url))?;
+        let timezone = file
+            .timezone
+            .parse::<Tz>()
+            .map_err(|_| anyhow!("invalid timezone: {}", file.timezone))?;
+        for (key, expr) in [
+            ("schedule.post", &file.schedule.post),
+            ("schedule.update_markov", &file.schedule.update_markov),
+            ("schedule.reconcile", &file.schedule.reconcile),
+        ] {
+            CronSchedule::parse(expr, timezone).with_context(|| format!("invalid {}", key))?;
+        }
+        ensure!(
+            (0..=100).contains(&file.default_freq),
+            "default_freq must be between 0 and 100"
+        );
+        ensure!(file.reconcile_days > 0, "reconcile_days must be positive");
+        ensure!(
+            file.crawl.requests_per_second.is_finite()
+                && file.crawl.requests_per_second >= CrawlConfig::MIN_REQUESTS_PER_SECOND,
+            "crawl.requests_per_second must be at least {}",
+            CrawlConfig::MIN_REQUESTS_PER_SECOND
+        );
+        ensure!(
+            file.rate_limit.max_count > 0,
+            "rate_limit.max_count must be positive"
+        );
+        let (min_order, max_order) = file.markov.orders();
+        ensure!(
+            0 < min_order && min_order <= max_order,
+            "markov.min_order must be between 1 and markov.order"
+        );
+        ensure!(
+            (0.0..=1.0).contains(&file.novelty.max_overlap_ratio),
+            "novelty.max_overlap_ratio must be between 0 and 1"
+        );
+        let block_message_regex = RegexSet::new(&file.block_message_patterns)
+            .context("invalid block_message_patterns")?;
+
+        let constraints = Purpose::ALL
+            .into_iter()
+            .map(|purpose| {
+                let mut constraints = purpose.default_constraints();
+                if let Some(overrides) = file.constraints.get(&purpose) {
+                    constraints = constraints
+                        .overridden_with(overrides)
+                        .with_context(|| format!("invalid constraints.{:?}", purpose))?;
+                }
+                if let Some(v) = getenv(purpose.env_key()) {
+                    constraints = constraints
+                        .overridden_by(&v)
+                        .with_context(|| format!("invalid {}", purpose.env_key()))?;
+                }
+                Ok((purpose, constraints))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            bot_id: file.bot_id,
+            bot_user_id: file.bot_user_id,
+            target_user_ids: file.target_user_ids,
+            cron_channel_id: file.cron_channel_id,
+            base_url: file.base_url,
+            timezone,
+            block_message_regex,
+            default_freq: file.default_freq,
+            reconcile_days: file.reconcile_days,
+            crawl: file.crawl,
+            schedule: file.schedule,
+            rate_limit: file.rate_limit,
+            markov: file.markov,
+            novelty: file.novelty,
+            keyword_reply: file.keyword_reply,
+            constraints,
+            admin: file.admin,
+        })
+    }
+
+    /// デフォルトの人格のユーザーの UUID
+    pub fn default_target_user_id(&self) -> &str {
+        &self.target_user_ids[0]
+    }
+
+    /// user_id が設定ファイルで指定された BOT のオーナーなら true を返す
+    pub fn is_owner(&self, user_id: &str) -> bool {
+        self.admin.user_ids.iter().any(|id| id == user_id)
+    }
+
+    /// markov chain を DB から作り直す必要がある変更があれば true を返す
+    pub fn requires_rebuild(&self, new: &Config) -> bool {
+        self.block_message_regex.patterns() != new.block_message_regex.patterns()
+            || self.markov.orders() != new.markov.orders()
+    }
+
+    /// new との差分のうち、再起動しないと反映されない項目の名前を返す
+    pub fn restart_required_changes(&self, new: &Config) -> Vec<&'static str> {
+        [
+            ("bot_id", self.bot_id != new.bot_id),
+            ("base_url", self.base_url != new.base_url),
+            ("timezone", self.timezone != new.timezone),
+            ("bot_user_id", self.bot_user_id != new.bot_user_id),
+            (
+                "cron_channel_id",
+                self.cron_channel_id != new.cron_channel_id,
+            ),
+            ("schedule", self.schedule != new.schedule),
+            ("rate_limit", self.rate_limit != new.rate_limit),
+            (
+                "admin.http_port",
+                self.admin.http_port != new.admin.http_port,
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| name)
+        .collect()
+    }
+}
+
+fn parse_env(v: &str, kind: EnvKind) -> anyhow::Result<Value> {
+    let value = match kind {
+        EnvKind::String => Value::String(v.to_string()),
+        EnvKind::List => Value::Sequence(
+            v.split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|s| Value::String(s.to_string()))
+                .collect(),
+        ),
+        EnvKind::Number => match serde_yaml::from_str(v)? {
+            value @ Value::Number(_) => value,
+            _ => bail!("expected a number, found `{}`", v),
+        },
+        EnvKind::Bool => match v {
+            "1" | "true" => Value::Bool(true),
+            "0" | "false" => Value::Bool(false),
+            _ => bail!("expected 1 or 0, found `{}`", v),
+        },
+    };
+    Ok(value)
+}
+
+/// value の path の位置に v を設定する (途中の mapping は必要に応じて作成する)
+fn set_path(value: &mut Value, path: &[&str], v: Value) -> anyhow::Result<()> {
+    let Some((last, parents)) = path.split_last() else {
+        *value = v;
+        return Ok(());
+    };
+    let mut current = value;
+    for key in parents {
+        let Value::Mapping(mapping) = current else {
+            bail!("`{}` is not a mapping", key);
+        };
+        current = mapping
+            .entry(Value::String(key.to_string()))
+            .or_insert_with(|| Value::Mapping(Mapping::new()));
+    }
+    let Value::Mapping(mapping) = current else {
+        bail!("parent of `{}` is not a mapping", last);
+    };
+    mapping.insert(Value::String(last.to_string()), v);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINIMAL: &str = r#"
+bot_id: "bot"
+bot_user_id: "bot-user"
+target_user_ids: ["user-a", "user-b"]
+cron_channel_id: "channel"
+"#;
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn test_parse_minimal() {
+        let config = Config::parse(Some(MINIMAL), no_env).unwrap();
+        assert_eq!(config.default_target_user_id(), "user-a");
+        assert_eq!(config.default_freq, 20);
+        assert_eq!(config.timezone, chrono_tz::Asia::Tokyo);
+        assert_eq!(config.markov.orders(), (2, 2));
+        assert!(config.block_message_regex.is_match("https://example.com/a"));
+        assert!(config.block_message_regex.is_match("%command"));
+        assert_eq!(config.schedule, ScheduleConfig::default());
+        assert_eq!(
+            config.constraints[&Purpose::Cron],
+            Purpose::Cron.default_constraints()
+        );
+    }
+
+    #[test]
+    fn test_parse_repository_config() {
+        let config = Config::parse(Some(include_str!("../config.yaml")), no_env).unwrap();
+        assert_eq!(
+            config.constraints[&Purpose::Random],
+            Purpose::Random.default_constraints()
+        );
+        assert!(config.block_message_regex.is_match("https://example.com/a"));
+    }
+
+    #[test]
+    fn test_parse_full() {
+        let content = format!(
+            r#"{}
+block_message_patterns: ['^:awoo:$']
+default_freq: 50
+markov:
+  order: 4
+  min_order: 1
+constraints:
+  random:
+    max_chars: 50
+"#,
+            MINIMAL
+        );
+        let config = Config::parse(Some(&content), no_env).unwrap();
+        assert!(config.block_message_regex.is_match(":awoo:"));
+        assert_eq!(config.default_freq, 50);
+        assert_eq!(config.markov.orders(), (1, 4));
+        assert_eq!(config.constraints[&Purpose::Random].max_chars, 50);
+        assert!(config.constraints[&Purpose::Random].require_non_stamp);
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let getenv = |key: &str| match key {
+            "TARGET_USER_IDS" => Some("user-c, user-d".to_string()),
+            "MARKOV_ORDER" => Some("3".to_string()),
+            "KEYWORD_REPLY" => Some("1".to_string()),
+            "TIMEZONE" => Some("UTC".to_string()),
+            "GENERATE_CONSTRAINTS_DM" => Some("max_chars=10".to_string()),
+            _ => None,
+        };
+        let config = Config::parse(Some(MINIMAL), getenv).unwrap();
+        assert_eq!(config.target_user_ids, vec!["user-c", "user-d"]);
+        assert_eq!(config.markov.orders(), (3, 3));
+        assert!(config.keyword_reply);
+        assert_eq!(config.timezone, chrono_tz::UTC);
+        assert_eq!(config.constraints[&Purpose::DirectMessage].max_chars, 10);
+    }
+
+    #[test]
+    fn test_env_only() {
+        let getenv = |key: &str| match key {
+            "BOT_ID" | "BOT_USER_ID" | "TARGET_USER_IDS" | "CRON_CHANNEL_ID" => {
+                Some("id".to_string())
+            }
+            _ => None,
+        };
+        assert!(Config::parse(None, getenv).is_ok());
+        assert!(Config::parse(None, no_env).is_err());
+    }
+
+    #[test]
+    fn test_changes() {
+        let old = Config::parse(Some(MINIMAL), no_env).unwrap();
+        let new = Config::parse(
+            Some(&format!(
+                "{}block_message_patterns: ['^%']\ndefault_freq: 10\n",
+                MINIMAL
+            )),
+            no_env,
+        )
+        .unwrap();
+        assert!(old.requires_rebuild(&new));
+        assert!(old.restart_required_changes(&new).is_empty());
+
+        let new = Config::parse(
+            Some(&format!("{}rate_limit:\n  max_count: 1\n", MINIMAL)),
+            no_env,
+        )
+        .unwrap();
+        assert!(!old.requires_rebuild(&new));
+        assert_eq!(old.restart_required_changes(&new), vec!["rate_limit"]);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let invalid = |extra: &str| Config::parse(Some(&format!("{}{}", MINIMAL, extra)), no_env);
+        assert!(invalid("unknown_key: 1\n").is_err());
+        assert!(invalid("default_freq: 101\n").is_err());
+        assert!(invalid("timezone: Mars/Olympus\n").is_err());
+        assert!(invalid("schedule:\n  post: '0 0 25 * * *'\n").is_err());
+        assert!(invalid("schedule:\n  update_markov: every day\n").is_err());
+        assert!(invalid("schedule:\n  reconcile: '0 0 9 * *'\n").is_err());
+        assert!(invalid("crawl:\n  requests_per_second: 0\n").is_err());
+        assert!(invalid("crawl:\n  requests_per_second: 1e-20\n").is_err());
+        assert!(invalid("block_message_patterns: ['(']\n").is_err());
+        assert!(invalid("markov:\n  order: 1\n  min_order: 2\n").is_err());
+        assert!(invalid("constraints:\n  cron:\n    min_chars: 1000\n").is_err());
+        assert!(Config::parse(Some(MINIMAL), |key| (key == "MARKOV_ORDER")
+            .then(|| "two".to_string()))
+        .is_err());
+    }
+}
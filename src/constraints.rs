@@ -0,0 +1,246 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context as _};
+use serde::Deserialize;
+
+use crate::STAMP_REGEX;
+
+/// メッセージを生成する場面
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Purpose {
+    /// 定期投稿
+    Cron,
+    /// メンションへの返信
+    Mention,
+    /// 参加しているチャンネルでのランダムな返信
+    Random,
+    /// DM への返信
+    DirectMessage,
+}
+
+impl Purpose {
+    pub const ALL: [Purpose; 4] = [
+        Purpose::Cron,
+        Purpose::Mention,
+        Purpose::Random,
+        Purpose::DirectMessage,
+    ];
+
+    /// 制約を上書きする環境変数の名前
+    pub fn env_key(self) -> &'static str {
+        match self {
+            Purpose::Cron => "GENERATE_CONSTRAINTS_CRON",
+            Purpose::Mention => "GENERATE_CONSTRAINTS_MENTION",
+            Purpose::Random => "GENERATE_CONSTRAINTS_RANDOM",
+            Purpose::DirectMessage => "GENERATE_CONSTRAINTS_DM",
+        }
+    }
+
+    /// 設定で上書きされていないときの制約
+    pub fn default_constraints(self) -> Constraints {
+        match self {
+            Purpose::Cron => Constraints {
+                min_chars: 5,
+                require_non_stamp: true,
+                max_attempts: 20,
+                ..Default::default()
+            },
+            Purpose::Random => Constraints {
+                max_chars: 140,
+                require_non_stamp: true,
+                ..Default::default()
+            },
+            Purpose::Mention | Purpose::DirectMessage => Constraints::default(),
+        }
+    }
+}
+
+/// 生成したメッセージの長さや内容に対する制約
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Constraints {
+    pub min_chars: usize,
+    pub max_chars: usize,
+    pub min_tokens: usize,
+    pub max_tokens: usize,
+    /// スタンプ以外の単語を 1 つ以上含む必要があるか
+    pub require_non_stamp: bool,
+    /// 制約を満たすメッセージを生成するまでに試行する最大の回数
+    pub max_attempts: usize,
+}
+
+impl Default for Constraints {
+    fn default() -> Self {
+        Self {
+            min_chars: 1,
+            max_chars: 280,
+            min_tokens: 1,
+            max_tokens: 100,
+            require_non_stamp: false,
+            max_attempts: 10,
+        }
+    }
+}
+
+/// 設定ファイルで指定された、制約の一部を上書きする値
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConstraintsOverride {
+    pub min_chars: Option<usize>,
+    pub max_chars: Option<usize>,
+    pub min_tokens: Option<usize>,
+    pub max_tokens: Option<usize>,
+    pub require_non_stamp: Option<bool>,
+    pub max_attempts: Option<usize>,
+}
+
+impl Constraints {
+    /// 単語列 tokens がこの制約を満たしていれば true を返す
+    pub fn is_satisfied_by(&self, tokens: &[String]) -> bool {
+        let chars = tokens.iter().map(|t| t.chars().count()).sum::<usize>();
+        (self.min_chars..=self.max_chars).contains(&chars)
+            && (self.min_tokens..=self.max_tokens).contains(&tokens.len())
+            && (!self.require_non_stamp
+                || tokens
+                    .iter()
+                    .any(|t| !t.trim().is_empty() && !STAMP_REGEX.is_match(t)))
+    }
+
+    /// `key=value` をカンマ区切りで並べた文字列で、一部の値を上書きする
+    ///
+    /// **Example** `min_chars=5,max_chars=140,require_non_stamp=true`
+    pub fn overridden_by(mut self, s: &str) -> anyhow::Result<Self> {
+        for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow!("expected `key=value`, found `{}`", pair))?;
+            let (key, value) = (key.trim(), value.trim());
+            match key {
+                "min_chars" => self.min_chars = parse_value(key, value)?,
+                "max_chars" => self.max_chars = parse_value(key, value)?,
+                "min_tokens" => self.min_tokens = parse_value(key, value)?,
+                "max_tokens" => self.max_tokens = parse_value(key, value)?,
+                "require_non_stamp" => self.require_non_stamp = parse_value(key, value)?,
+                "max_attempts" => self.max_attempts = parse_value(key, value)?,
+                _ => bail!("unknown constraint `{}`", key),
+            }
+        }
+        self.validated()
+    }
+
+    /// overrides で指定された値のみを上書きする
+    pub fn overridden_with(self, overrides: &ConstraintsOverride) -> anyhow::Result<Self> {
+        Self {
+            min_chars: overrides.min_chars.unwrap_or(self.min_chars),
+            max_chars: overrides.max_chars.unwrap_or(self.max_chars),
+            min_tokens: overrides.min_tokens.unwrap_or(self.min_tokens),
+            max_tokens: overrides.max_tokens.unwrap_or(self.max_tokens),
+            require_non_stamp: overrides
+                .require_non_stamp
+                .unwrap_or(self.require_non_stamp),
+            max_attempts: overrides.max_attempts.unwrap_or(self.max_attempts),
+        }
+        .validated()
+    }
+
+    fn validated(self) -> anyhow::Result<Self> {
+        if self.min_chars > self.max_chars || self.min_tokens > self.max_tokens {
+            bail!("min must not be greater than max: {:?}", self);
+        }
+        if self.max_attempts == 0 {
+            bail!("max_attempts must be positive");
+        }
+        Ok(self)
+    }
+}
+
+fn parse_value<T>(key: &str, value: &str) -> anyhow::Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    value
+        .parse()
+        .with_context(|| format!("invalid value for `{}`: `{}`", key, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(s: &str) -> Vec<String> {
+        s.split(' ').map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn test_length_constraints() {
+        let constraints = Constraints {
+            min_chars: 3,
+            max_chars: 5,
+            min_tokens: 2,
+            max_tokens: 3,
+            ..Default::default()
+        };
+        assert!(constraints.is_satisfied_by(&tokens("ab cd")));
+        assert!(!constraints.is_satisfied_by(&tokens("abcd")));
+        assert!(!constraints.is_satisfied_by(&tokens("a b")));
+        assert!(!constraints.is_satisfied_by(&tokens("ab cd ef")));
+        assert!(!constraints.is_satisfied_by(&[]));
+    }
+
+    #[test]
+    fn test_require_non_stamp() {
+        let constraints = Constraints {
+            require_non_stamp: true,
+            ..Default::default()
+        };
+        assert!(!constraints.is_satisfied_by(&tokens(":awoo: :blob_pyon:")));
+        assert!(constraints.is_satisfied_by(&tokens(":awoo: わん")));
+    }
+
+    #[test]
+    fn test_overridden_by() {
+        let constraints = Constraints::default()
+            .overridden_by("min_chars=5, require_non_stamp=true")
+            .unwrap();
+        assert_eq!(
+            constraints,
+            Constraints {
+                min_chars: 5,
+                require_non_stamp: true,
+                ..Default::default()
+            }
+        );
+        assert!(Constraints::default().overridden_by("foo=1").is_err());
+        assert!(Constraints::default().overridden_by("min_chars").is_err());
+        assert!(Constraints::default()
+            .overridden_by("min_chars=-1")
+            .is_err());
+        assert!(Constraints::default()
+            .overridden_by("min_chars=1000")
+            .is_err());
+    }
+
+    #[test]
+    fn test_overridden_with() {
+        let constraints = Constraints::default()
+            .overridden_with(&ConstraintsOverride {
+                max_chars: Some(10),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(
+            constraints,
+            Constraints {
+                max_chars: 10,
+                ..Default::default()
+            }
+        );
+        assert!(Constraints::default()
+            .overridden_with(&ConstraintsOverride {
+                max_attempts: Some(0),
+                ..Default::default()
+            })
+            .is_err());
+    }
+}
@@ -1,58 +1,494 @@
-use std::{env, sync::Arc, thread, time::Duration};
+use std::{
+    collections::HashMap,
+    env,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
-use log::{debug, error};
+use anyhow::{ensure, Context as _};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use log::{debug, error, info, warn};
 use rand::Rng;
 use sqlx::MySqlPool;
-use tokio_cron_scheduler::{Job, JobScheduler};
+use tokio::{task::JoinHandle, time::sleep};
 use traq_ws_bot::utils::RateLimiter;
 
-use crate::{generate_message, model::api, update_markov_chain};
+use crate::{
+    config::config,
+    constraints::Purpose,
+    generate_message,
+    model::{
+        api::ApiClient,
+        db::{self, ScheduleRecord},
+    },
+    quiet::is_quiet,
+    reconcile_messages, update_markov_chain, Resource, POOL,
+};
 
+/// 設定ファイルの定期投稿を遅らせる最大の分数
+pub const CONFIG_POST_JITTER_MINUTES: u64 = 59;
+
+/// チャンネルごとの定期投稿の、投稿する時刻の最小の間隔
+pub const MIN_SCHEDULE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// 1 つのチャンネルに追加できる定期投稿の数
+pub const MAX_SCHEDULES_PER_CHANNEL: usize = 5;
+
+/// 時刻の間隔を調べるときに見る、次からの時刻の数
+const INTERVAL_SAMPLES: usize = 1000;
+
+/// タイムゾーン付きの cron 式
+#[derive(Debug, Clone)]
+pub struct CronSchedule {
+    schedule: ::cron::Schedule,
+    tz: Tz,
+}
+
+impl CronSchedule {
+    /// tz の時刻として cron 式を解釈する
+    pub fn parse(expr: &str, tz: Tz) -> anyhow::Result<Self> {
+        let schedule = expr
+            .parse()
+            .with_context(|| format!("invalid cron expression: {}", expr))?;
+        Ok(Self { schedule, tz })
+    }
+
+    /// after より後で最初の時刻を返す
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Tz>> {
+        self.schedule.after(&after.with_timezone(&self.tz)).next()
+    }
+
+    /// 次の時刻を返す
+    pub fn upcoming(&self) -> Option<DateTime<Tz>> {
+        self.next_after(Utc::now())
+    }
+
+    /// after より後の INTERVAL_SAMPLES 回の時刻のうち、連続する時刻の最小の間隔を返す
+    ///
+    /// 時刻が 2 回以上ない場合は None を返す
+    fn min_interval_after(&self, after: DateTime<Utc>) -> Option<Duration> {
+        let times = self
+            .schedule
+            .after(&after.with_timezone(&self.tz))
+            .take(INTERVAL_SAMPLES)
+            .collect::<Vec<_>>();
+        times
+            .windows(2)
+            .filter_map(|w| (w[1] - w[0]).to_std().ok())
+            .min()
+    }
+}
+
+/// schedule の時刻ごとに job を実行するタスクを起動する
+///
+/// 夏時間などでオフセットが変わっても、毎回 schedule のタイムゾーンで次の時刻を求める
+fn spawn_cron<F, Fut>(schedule: CronSchedule, mut job: F) -> JoinHandle<()>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut after = Utc::now();
+        while let Some(next) = schedule.next_after(after) {
+            let next = next.with_timezone(&Utc);
+            if let Ok(wait) = (next - Utc::now()).to_std() {
+                sleep(wait).await;
+            }
+            tokio::spawn(job());
+            // 止まっていた間の時刻はまとめて飛ばす
+            after = next.max(Utc::now());
+        }
+    })
+}
+
+/// 遅らせて実行するタスクの集まり
+///
+/// 予約したタスクはそれぞれ実行され、待っている間のタスクだけを取り消せる
+/// (実行を始めたタスクは、取り消されずに最後まで実行される)
+#[derive(Debug, Default)]
+struct DelayedTasks {
+    /// 予約した番号ごとの、待っている間のタスク
+    pending: Mutex<HashMap<u64, JoinHandle<()>>>,
+    next_id: AtomicU64,
+}
+
+impl DelayedTasks {
+    /// delay 後に task を実行するように予約する
+    fn schedule<F>(self: &Arc<Self>, delay: Duration, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let tasks = self.clone();
+        // 登録する前にタスクが待ち終わっても、登録されるまで pending の lock で待たせる
+        let mut pending = self.pending.lock().unwrap();
+        let handle = tokio::spawn(async move {
+            sleep(delay).await;
+            if tasks.pending.lock().unwrap().remove(&id).is_none() {
+                return;
+            }
+            task.await;
+        });
+        pending.insert(id, handle);
+    }
+
+    /// 待っている間のタスクをすべて取り消し、取り消した数を返す
+    fn cancel(&self) -> usize {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        for handle in pending.values() {
+            handle.abort();
+        }
+        pending.len()
+    }
+}
+
+/// 予定の ID ごとの、job のタスクと遅らせて実行する投稿
+type ScheduledJobs = HashMap<i64, (JoinHandle<()>, Arc<DelayedTasks>)>;
+
+/// 定期投稿の job を管理する
+///
+/// チャンネルごとの予定 (schedules テーブル) は、起動中に追加・削除できる
+/// cron 式はすべて tz の時刻として解釈する
+#[derive(Clone)]
+pub struct PostScheduler {
+    tz: Tz,
+    api: ApiClient,
+    rate_limiter: Arc<RateLimiter>,
+    jobs: Arc<Mutex<ScheduledJobs>>,
+}
+
+impl PostScheduler {
+    pub fn new(api: ApiClient, rate_limiter: Arc<RateLimiter>, tz: Tz) -> Self {
+        PostScheduler {
+            tz,
+            api,
+            rate_limiter,
+            jobs: Default::default(),
+        }
+    }
+
+    /// cron 式を解釈するタイムゾーン
+    pub fn tz(&self) -> Tz {
+        self.tz
+    }
+
+    /// cron 式を検証する
+    pub fn parse(&self, cron: &str) -> anyhow::Result<CronSchedule> {
+        CronSchedule::parse(cron, self.tz)
+    }
+
+    /// cron の時刻から最大 jitter_minutes 分遅らせて、channel_id に投稿するタスクを起動する
+    fn spawn_post(
+        &self,
+        cron: &str,
+        channel_id: &str,
+        jitter_minutes: u64,
+        delayed: Arc<DelayedTasks>,
+    ) -> anyhow::Result<JoinHandle<()>> {
+        let schedule = self.parse(cron)?;
+        let tz = self.tz;
+        let api = self.api.clone();
+        let rate_limiter = self.rate_limiter.clone();
+        let channel_id = channel_id.to_string();
+        let handle = spawn_cron(schedule, move || {
+            let api = api.clone();
+            let rate_limiter = rate_limiter.clone();
+            let channel_id = channel_id.clone();
+            let next_span = rand::thread_rng().gen_range(0..=jitter_minutes);
+            debug!("scheduled at {} minutes later on {}", next_span, channel_id);
+            delayed.schedule(Duration::from_secs(next_span * 60), async move {
+                match is_quiet(POOL.get().unwrap(), &channel_id, tz).await {
+                    Ok(false) => {}
+                    Ok(true) => {
+                        info!("skipped a scheduled post in quiet hours of {}", channel_id);
+                        return;
+                    }
+                    Err(e) => {
+                        error!("Failed to get quiet hours: {:#}", e);
+                        return;
+                    }
+                }
+                let Some(message) =
+                    generate_message(config().default_target_user_id(), Purpose::Cron).await
+                else {
+                    info!("Failed to generate a message");
+                    return;
+                };
+                if let Err(e) = api
+                    .post_message(channel_id, message, Some(&rate_limiter))
+                    .await
+                {
+                    error!("{}", e);
+                }
+            });
+            async {}
+        });
+        Ok(handle)
+    }
+
+    /// 予定の job を登録する
+    fn register(&self, schedule: &ScheduleRecord) -> anyhow::Result<()> {
+        let delayed = Arc::new(DelayedTasks::default());
+        let handle = self.spawn_post(
+            &schedule.cron,
+            &schedule.channel_id,
+            schedule.jitter_minutes as u64,
+            delayed.clone(),
+        )?;
+        self.jobs
+            .lock()
+            .unwrap()
+            .insert(schedule.id, (handle, delayed));
+        Ok(())
+    }
+
+    /// DB に保存されている予定をすべて登録する
+    pub async fn load(&self, pool: &MySqlPool) -> anyhow::Result<()> {
+        for schedule in db::get_schedules(pool).await? {
+            if let Err(e) = self.register(&schedule) {
+                warn!("Failed to register schedule {}: {:#}", schedule.id, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// channel_id に予定を追加して登録し、その ID を返す
+    pub async fn add(
+        &self,
+        pool: &MySqlPool,
+        channel_id: &str,
+        cron: &str,
+        jitter_minutes: i32,
+    ) -> anyhow::Result<i64> {
+        // 保存する前に cron 式を検証する
+        let schedule = self.parse(cron)?;
+        if let Some(interval) = schedule.min_interval_after(Utc::now()) {
+            ensure!(
+                interval >= MIN_SCHEDULE_INTERVAL,
+                "posts must be at least {} minutes apart",
+                MIN_SCHEDULE_INTERVAL.as_secs() / 60
+            );
+        }
+        let count = db::get_channel_schedules(pool, channel_id).await?.len();
+        ensure!(
+            count < MAX_SCHEDULES_PER_CHANNEL,
+            "a channel can have at most {} schedules",
+            MAX_SCHEDULES_PER_CHANNEL
+        );
+
+        let id = db::insert_schedule(pool, channel_id, cron, jitter_minutes).await?;
+        let schedule = ScheduleRecord {
+            id,
+            channel_id: channel_id.to_string(),
+            cron: cron.to_string(),
+            jitter_minutes,
+        };
+        if let Err(e) = self.register(&schedule) {
+            db::delete_schedule(pool, channel_id, id).await?;
+            return Err(e);
+        }
+        Ok(id)
+    }
+
+    /// channel_id の予定を削除し、削除できたかどうかを返す
+    ///
+    /// 遅らせて待っている投稿も取り消す (投稿を始めたものは取り消さない)
+    pub async fn remove(
+        &self,
+        pool: &MySqlPool,
+        channel_id: &str,
+        id: i64,
+    ) -> anyhow::Result<bool> {
+        if !db::delete_schedule(pool, channel_id, id).await? {
+            return Ok(false);
+        }
+        let removed = self.jobs.lock().unwrap().remove(&id);
+        if let Some((handle, delayed)) = removed {
+            handle.abort();
+            let cancelled = delayed.cancel();
+            if cancelled > 0 {
+                info!(
+                    "{} delayed posts of schedule {} were cancelled",
+                    cancelled, id
+                );
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// 設定ファイルの定期実行と、DB に保存されている定期投稿を開始する
 pub async fn start_scheduling(
     pool: &'static MySqlPool,
-    channel_id: &'static str,
-    rate_limiter: Arc<RateLimiter>,
-) -> anyhow::Result<tokio::task::JoinHandle<()>> {
-    let main_scheduler = JobScheduler::new()?;
+    channel_id: String,
+    resource: Resource,
+) -> anyhow::Result<()> {
+    let post_scheduler = &resource.scheduler;
 
     dotenv::dotenv().ok();
     let many_msg = env::var("MANY_MSG").map(|s| s == "1").unwrap_or(false);
-    let cron_schedule = if many_msg {
-        "1/4 * * * * *"
+    let schedule = config().schedule.clone();
+    let update_markov = post_scheduler
+        .parse(&schedule.update_markov)
+        .context("invalid schedule.update_markov")?;
+    let reconcile = post_scheduler
+        .parse(&schedule.reconcile)
+        .context("invalid schedule.reconcile")?;
+
+    if many_msg {
+        post_scheduler.spawn_post("1/4 * * * * *", &channel_id, 0, Default::default())?;
     } else {
-        // "0 0,20,40 0-15,22-23 * * *"
-        "0 0 0-15,22-23 * * *"
-    };
-
-    // 日本時間で 0 0 0,7-23 * * * (cron は UTC)
-    let post_job = Job::new_async(cron_schedule, move |_uuid, _lock| {
-        let rate_limiter = rate_limiter.clone();
-        Box::pin(async move {
-            let next_span = rand::thread_rng().gen_range(1..60);
-            debug!("scheduled at {} minutes later", next_span);
-            if !many_msg {
-                thread::sleep(Duration::from_secs(next_span * 60));
-            }
-            let message = generate_message();
-            if let Err(e) =
-                api::post_message(channel_id.to_string(), message, Some(&rate_limiter)).await
-            {
+        post_scheduler
+            .spawn_post(
+                &schedule.post,
+                &channel_id,
+                CONFIG_POST_JITTER_MINUTES,
+                Default::default(),
+            )
+            .context("invalid schedule.post")?;
+    }
+
+    let update_resource = resource.clone();
+    spawn_cron(update_markov, move || {
+        let resource = update_resource.clone();
+        async move {
+            let res = update_markov_chain(pool, &resource.api).await;
+            if let Err(e) = res {
                 error!("{}", e);
             }
-        })
-    })?;
+        }
+    });
 
-    let update_markov_job = Job::new_async("0 0 0 * * *", |_uuid, _lock| {
-        Box::pin(async {
-            let res = update_markov_chain(pool).await;
+    let reconcile_resource = resource.clone();
+    spawn_cron(reconcile, move || {
+        let resource = reconcile_resource.clone();
+        async move {
+            let res = reconcile_messages(pool, &resource.api).await;
             if let Err(e) = res {
                 error!("{}", e);
             }
-        })
-    })?;
+        }
+    });
+
+    post_scheduler.load(pool).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_cron_schedule() {
+        // 日本時間の 0 時と 7~23 時
+        let schedule = CronSchedule::parse("0 0 0,7-23 * * *", chrono_tz::Asia::Tokyo).unwrap();
+        let next = schedule.next_after(utc("2026-01-01T15:30:00Z")).unwrap();
+        assert_eq!(next.with_timezone(&Utc), utc("2026-01-01T22:00:00Z"));
+        assert_eq!(
+            next,
+            chrono_tz::Asia::Tokyo
+                .with_ymd_and_hms(2026, 1, 2, 7, 0, 0)
+                .unwrap()
+        );
+        let next = schedule.next_after(utc("2026-01-01T14:30:00Z")).unwrap();
+        assert_eq!(next.with_timezone(&Utc), utc("2026-01-01T15:00:00Z"));
+
+        assert!(CronSchedule::parse("0 0 25 * * *", chrono_tz::UTC).is_err());
+    }
+
+    #[test]
+    fn test_min_interval() {
+        let interval = |expr: &str| {
+            CronSchedule::parse(expr, chrono_tz::Asia::Tokyo)
+                .unwrap()
+                .min_interval_after(utc("2026-01-01T00:00:00Z"))
+        };
+        assert_eq!(interval("* * * * * *"), Some(Duration::from_secs(1)));
+        assert_eq!(interval("0 */5 * * * *"), Some(Duration::from_secs(5 * 60)));
+        assert_eq!(
+            interval("0 0 0,7-23 * * *"),
+            Some(Duration::from_secs(60 * 60))
+        );
+        // 毎月 1 日にだけ 1 分間隔になる
+        assert_eq!(interval("0 0,1 9 1 * *"), Some(Duration::from_secs(60)));
+        assert_eq!(interval("0 0 9 1 1 * 2026"), None);
+    }
+
+    #[test]
+    fn test_cron_schedule_dst() {
+        // 夏時間の前後で UTC での時刻が変わる
+        let schedule = CronSchedule::parse("0 0 9 * * *", chrono_tz::America::New_York).unwrap();
+        let next = schedule.next_after(utc("2026-03-07T00:00:00Z")).unwrap();
+        assert_eq!(next.with_timezone(&Utc), utc("2026-03-07T14:00:00Z"));
+        let next = schedule.next_after(utc("2026-03-08T20:00:00Z")).unwrap();
+        assert_eq!(next.with_timezone(&Utc), utc("2026-03-09T13:00:00Z"));
+    }
+
+    #[tokio::test]
+    async fn test_spawn_cron() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let schedule = CronSchedule::parse("* * * * * *", chrono_tz::UTC).unwrap();
+        let counter = count.clone();
+        let handle = spawn_cron(schedule, move || {
+            let counter = counter.clone();
+            async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        sleep(Duration::from_millis(1500)).await;
+        handle.abort();
+        let ran = count.load(Ordering::SeqCst);
+        assert!((1..=2).contains(&ran));
+    }
+
+    #[tokio::test]
+    async fn test_delayed_tasks() {
+        let tasks = Arc::new(DelayedTasks::default());
+        let count = Arc::new(AtomicUsize::new(0));
 
-    main_scheduler.add(post_job)?;
-    main_scheduler.add(update_markov_job)?;
+        // 後から予約しても、前のタスクは取り消されない
+        for delay in [100, 0] {
+            let counter = count.clone();
+            tasks.schedule(Duration::from_millis(delay), async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert_eq!(tasks.cancel(), 0);
 
-    Ok(main_scheduler.start()?)
+        // 待っている間のタスクだけを取り消す
+        let started = Arc::new(AtomicBool::new(false));
+        let finished = Arc::new(AtomicBool::new(false));
+        let (start_flag, finish_flag) = (started.clone(), finished.clone());
+        tasks.schedule(Duration::ZERO, async move {
+            start_flag.store(true, Ordering::SeqCst);
+            sleep(Duration::from_millis(100)).await;
+            finish_flag.store(true, Ordering::SeqCst);
+        });
+        let counter = count.clone();
+        tasks.schedule(Duration::from_millis(100), async move {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        sleep(Duration::from_millis(50)).await;
+        assert!(started.load(Ordering::SeqCst));
+        assert_eq!(tasks.cancel(), 1);
+        sleep(Duration::from_millis(200)).await;
+        assert!(finished.load(Ordering::SeqCst));
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
 }
@@ -0,0 +1,319 @@
+//! テスト用の traQ API (v3) の偽物
+//!
+//! メッセージの検索、投稿、BOT のチャンネルへの参加と退出だけを実装している
+//! テスト全体で 1 つのサーバーを共有するので、テストごとに別のユーザーやチャンネルを使う
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::Ipv4Addr,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use chrono::{DateTime, FixedOffset, Utc};
+use once_cell::sync::Lazy;
+use rocket::{
+    config::{LogLevel, Shutdown},
+    fairing::AdHoc,
+    http::{Header, Status},
+    request::{FromRequest, Outcome},
+    Request, Responder, State,
+};
+
+use crate::model::{
+    api::ApiClient,
+    traq::{BotChannelActionRequest, Message, MessageSearchResult, PostMessageRequest},
+};
+
+/// 偽の traQ が受け付けるアクセストークン
+const TOKEN: &str = "fake-token";
+
+/// 偽の traQ に登録されている BOT の UUID
+const BOT_ID: &str = "fake-bot";
+
+/// traQ の検索で取得できる件数の上限
+const SEARCH_HITS_LIMIT: usize = 10000;
+
+/// 1 回の検索で取得できる件数の上限
+const SEARCH_LIMIT_MAX: usize = 100;
+
+#[derive(Debug, Default)]
+struct FakeTraqState {
+    /// 検索の対象になるメッセージ
+    messages: Vec<Message>,
+    /// BOT が投稿したメッセージ
+    posted: Vec<Message>,
+    /// BOT が参加しているチャンネル
+    joined: HashSet<String>,
+    /// channel_id ごとの、投稿に 429 を返す残りの回数
+    rate_limited: HashMap<String, u32>,
+}
+
+pub struct FakeTraq {
+    base_url: String,
+    state: Arc<Mutex<FakeTraqState>>,
+}
+
+static FAKE_TRAQ: Lazy<FakeTraq> = Lazy::new(FakeTraq::start);
+
+/// 偽の traQ を (まだ起動していなければ) 起動して返す
+pub fn fake_traq() -> &'static FakeTraq {
+    &FAKE_TRAQ
+}
+
+impl FakeTraq {
+    fn start() -> Self {
+        let state = Arc::new(Mutex::new(FakeTraqState::default()));
+        let server_state = ServerState {
+            token: TOKEN.to_string(),
+            state: state.clone(),
+        };
+
+        let (tx, rx) = mpsc::sync_channel(1);
+        let rocket_config = rocket::Config {
+            address: Ipv4Addr::LOCALHOST.into(),
+            port: 0,
+            log_level: LogLevel::Off,
+            shutdown: Shutdown {
+                ctrlc: false,
+                signals: Default::default(),
+                ..Default::default()
+            },
+            ..rocket::Config::default()
+        };
+        // テストごとの runtime が終了しても止まらないように、専用のスレッドで動かす
+        thread::spawn(move || {
+            let _ = rocket::execute(
+                rocket::custom(rocket_config)
+                    .manage(server_state)
+                    .mount(
+                        "/api/v3",
+                        rocket::routes![
+                            routes::search_messages,
+                            routes::post_message,
+                            routes::bot_action
+                        ],
+                    )
+                    .attach(AdHoc::on_liftoff("port", move |rocket| {
+                        Box::pin(async move {
+                            let _ = tx.send(rocket.config().port);
+                        })
+                    }))
+                    .launch(),
+            )
+            .map_err(|e| panic!("failed to launch fake traQ: {}", e.kind()));
+        });
+        let port = rx.recv().expect("fake traQ did not start");
+
+        FakeTraq {
+            base_url: format!("http://127.0.0.1:{}/api/v3", port),
+            state,
+        }
+    }
+
+    /// 偽の traQ に接続するクライアントを作る
+    pub fn client(&self) -> ApiClient {
+        ApiClient::new(&self.base_url, BOT_ID, TOKEN).unwrap()
+    }
+
+    /// 検索の対象になるメッセージを追加する
+    pub fn add_messages(&self, messages: impl IntoIterator<Item = Message>) {
+        self.state.lock().unwrap().messages.extend(messages);
+    }
+
+    /// channel_id に投稿されたメッセージの本文を、投稿された順に返す
+    pub fn posted(&self, channel_id: &str) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .posted
+            .iter()
+            .filter(|m| m.channel_id == channel_id)
+            .map(|m| m.content.clone())
+            .collect()
+    }
+
+    /// BOT が channel_id に参加しているかどうか
+    pub fn is_joined(&self, channel_id: &str) -> bool {
+        self.state.lock().unwrap().joined.contains(channel_id)
+    }
+
+    /// channel_id への次の times 回の投稿に 429 を返すようにする
+    pub fn rate_limit(&self, channel_id: &str, times: u32) {
+        self.state
+            .lock()
+            .unwrap()
+            .rate_limited
+            .insert(channel_id.to_string(), times);
+    }
+}
+
+/// テスト用のメッセージを作る
+pub fn message(id: &str, user_id: &str, channel_id: &str, created_at: DateTime<Utc>) -> Message {
+    Message {
+        id: id.to_string(),
+        user_id: user_id.to_string(),
+        channel_id: channel_id.to_string(),
+        content: format!("message {}", id),
+        created_at: created_at.into(),
+        updated_at: created_at.into(),
+        pinned: false,
+        stamps: Vec::new(),
+        thread_id: None,
+    }
+}
+
+struct ServerState {
+    /// `Authorization: Bearer {token}` で指定する必要があるトークン
+    token: String,
+    state: Arc<Mutex<FakeTraqState>>,
+}
+
+/// 正しいトークンが指定されたリクエスト
+struct Bot;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Bot {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(state) = request.rocket().state::<ServerState>() else {
+            return Outcome::Error((Status::InternalServerError, ()));
+        };
+        let authorized = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .is_some_and(|token| token == state.token);
+        if authorized {
+            Outcome::Success(Bot)
+        } else {
+            Outcome::Error((Status::Unauthorized, ()))
+        }
+    }
+}
+
+#[derive(Responder)]
+enum Reply {
+    #[response(status = 200, content_type = "json")]
+    Ok(String),
+    #[response(status = 201, content_type = "json")]
+    Created(String),
+    #[response(status = 204)]
+    NoContent(()),
+    #[response(status = 429)]
+    TooManyRequests((), Header<'static>),
+}
+
+// route ごとに生成される uri! 用の macro (re-export) は使わないので、route の module に限って許可する
+#[allow(unused_imports)]
+mod routes {
+    use super::*;
+
+    fn parse_time(value: Option<&str>) -> Result<Option<DateTime<FixedOffset>>, Status> {
+        value
+            .map(DateTime::parse_from_rfc3339)
+            .transpose()
+            .map_err(|_| Status::BadRequest)
+    }
+
+    /// メッセージを検索する
+    ///
+    /// after と before は traQ と同じく、その時刻ちょうどのメッセージを含まない
+    #[allow(clippy::too_many_arguments)]
+    #[rocket::get("/messages?<from>&<limit>&<offset>&<sort>&<after>&<before>")]
+    pub(super) fn search_messages(
+        _bot: Bot,
+        state: &State<ServerState>,
+        from: Option<&str>,
+        limit: Option<usize>,
+        offset: Option<usize>,
+        sort: Option<&str>,
+        after: Option<&str>,
+        before: Option<&str>,
+    ) -> Result<Reply, Status> {
+        let limit = limit.unwrap_or(20);
+        if limit > SEARCH_LIMIT_MAX {
+            return Err(Status::BadRequest);
+        }
+        let after = parse_time(after)?;
+        let before = parse_time(before)?;
+
+        let state = state.state.lock().unwrap();
+        let mut hits = state
+            .messages
+            .iter()
+            .filter(|m| from.is_none_or(|from| m.user_id == from))
+            .filter(|m| after.is_none_or(|after| after < m.created_at))
+            .filter(|m| before.is_none_or(|before| m.created_at < before))
+            .cloned()
+            .collect::<Vec<_>>();
+        match sort.unwrap_or("-createdAt") {
+            "createdAt" => hits.sort_by_key(|m| m.created_at),
+            "-createdAt" => hits.sort_by_key(|m| std::cmp::Reverse(m.created_at)),
+            _ => return Err(Status::BadRequest),
+        }
+        let result = MessageSearchResult {
+            total_hits: hits.len().min(SEARCH_HITS_LIMIT),
+            hits: hits
+                .into_iter()
+                .take(SEARCH_HITS_LIMIT)
+                .skip(offset.unwrap_or(0))
+                .take(limit)
+                .collect(),
+        };
+        Ok(Reply::Ok(serde_json::to_string(&result).unwrap()))
+    }
+
+    /// channel_id にメッセージを投稿する
+    #[rocket::post("/channels/<channel_id>/messages", data = "<body>")]
+    pub(super) fn post_message(
+        _bot: Bot,
+        state: &State<ServerState>,
+        channel_id: &str,
+        body: String,
+    ) -> Result<Reply, Status> {
+        let request: PostMessageRequest =
+            serde_json::from_str(&body).map_err(|_| Status::BadRequest)?;
+
+        let mut state = state.state.lock().unwrap();
+        if let Some(remaining @ 1..) = state.rate_limited.get_mut(channel_id) {
+            *remaining -= 1;
+            return Ok(Reply::TooManyRequests((), Header::new("Retry-After", "0")));
+        }
+        let posted = Message {
+            content: request.content,
+            ..message(
+                &format!("posted-{}", state.posted.len()),
+                BOT_ID,
+                channel_id,
+                Utc::now(),
+            )
+        };
+        state.posted.push(posted.clone());
+        Ok(Reply::Created(serde_json::to_string(&posted).unwrap()))
+    }
+
+    /// BOT をチャンネルに参加 (join) または退出 (leave) させる
+    #[rocket::post("/bots/<bot_id>/actions/<action>", data = "<body>")]
+    pub(super) fn bot_action(
+        _bot: Bot,
+        state: &State<ServerState>,
+        bot_id: &str,
+        action: &str,
+        body: String,
+    ) -> Result<Reply, Status> {
+        let request: BotChannelActionRequest =
+            serde_json::from_str(&body).map_err(|_| Status::BadRequest)?;
+
+        let mut state = state.state.lock().unwrap();
+        if bot_id != BOT_ID {
+            return Err(Status::NotFound);
+        }
+        match action {
+            "join" => state.joined.insert(request.channel_id),
+            "leave" => state.joined.remove(&request.channel_id),
+            _ => return Err(Status::NotFound),
+        };
+        Ok(Reply::NoContent(()))
+    }
+}
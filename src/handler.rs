@@ -1,52 +1,70 @@
-use log::error;
-use once_cell::sync::Lazy;
+use log::{debug, error, info};
 use rand::Rng;
-use regex::Regex;
 use sqlx::MySqlPool;
 use traq_ws_bot::{events::payload, utils::is_mentioned_message};
 
 use crate::{
-    generate_message,
-    model::{
-        api,
-        db::{get_frequency, update_frequency},
-    },
-    Resource, BOT_USER_ID, FREQUENCIES_CACHE, POOL,
+    apply_message_changes,
+    command::{self, COMMANDS},
+    config::config,
+    constraints::Purpose,
+    generate_reply, learn_streamed_message,
+    model::db::{get_frequency, MessageChange, MessageRecord},
+    quiet::is_quiet,
+    Resource, FREQUENCIES_CACHE, POOL,
 };
 
-const DEFAULT_FREQ: i64 = 20;
-
-pub async fn join_handler(payload: payload::Joined) {
-    let res = api::post_message(
-        payload.channel.id,
-        "参加しました :blob_pyon:".to_string(),
-        None,
-    )
-    .await;
+pub async fn join_handler(payload: payload::Joined, resource: Resource) {
+    let res = resource
+        .api
+        .post_message(
+            payload.channel.id,
+            "参加しました :blob_pyon:".to_string(),
+            None,
+        )
+        .await;
     if let Err(e) = res {
         error!("Failed to post message: {}", e);
     }
 }
 
-pub async fn left_handler(payload: payload::Left) {
-    let res = api::post_message(
-        payload.channel.id,
-        "退出しました :blob_speedy_roll_inverse:".to_string(),
-        None,
-    )
-    .await;
+pub async fn left_handler(payload: payload::Left, resource: Resource) {
+    let res = resource
+        .api
+        .post_message(
+            payload.channel.id,
+            "退出しました :blob_speedy_roll_inverse:".to_string(),
+            None,
+        )
+        .await;
     if let Err(e) = res {
         error!("Failed to post message: {}", e);
     }
 }
 
-pub async fn direct_message_handler(payload: payload::DirectMessageCreated) {
+pub async fn direct_message_handler(payload: payload::DirectMessageCreated, resource: Resource) {
     if payload.message.user.bot {
         return;
     }
 
-    let res_message = generate_message();
-    let res = api::post_message(payload.message.channel_id, res_message, None).await;
+    if command::dispatch(&COMMANDS, &resource, &payload.message, true).await {
+        return;
+    }
+
+    let Some(res_message) = generate_reply(
+        config().default_target_user_id(),
+        Purpose::DirectMessage,
+        &payload.message.text,
+    )
+    .await
+    else {
+        info!("Failed to generate a message");
+        return;
+    };
+    let res = resource
+        .api
+        .post_message(payload.message.channel_id, res_message, None)
+        .await;
     if let Err(e) = res {
         error!("Failed to post message: {}", e);
     }
@@ -57,19 +75,21 @@ pub async fn non_mentioned_message_handler(payload: payload::MessageCreated, res
         return;
     }
 
-    if is_mentioned_message(&payload.message, BOT_USER_ID) {
+    if is_mentioned_message(&payload.message, &config().bot_user_id) {
         return;
     }
 
     let channel_id = payload.message.channel_id;
     let Some(freq) = get_frequency_with_cache(POOL.get().unwrap(), channel_id.clone()).await else {
         error!("Failed to get frequency");
-        let res = api::post_message(
-            channel_id,
-            "頻度の取得に失敗しました :Hyperblob:".to_string(),
-            None,
-        )
-        .await;
+        let res = resource
+            .api
+            .post_message(
+                channel_id,
+                "頻度の取得に失敗しました :Hyperblob:".to_string(),
+                None,
+            )
+            .await;
         if let Err(e) = res {
             error!("Failed to post message: {}", e);
         }
@@ -80,137 +100,126 @@ pub async fn non_mentioned_message_handler(payload: payload::MessageCreated, res
         return;
     }
 
-    let res_message = generate_message();
-    let res = api::post_message(channel_id, res_message, Some(&resource)).await;
+    match is_quiet(POOL.get().unwrap(), &channel_id, resource.scheduler.tz()).await {
+        Ok(false) => {}
+        Ok(true) => {
+            debug!("quiet hours in {}", channel_id);
+            return;
+        }
+        Err(e) => {
+            error!("Failed to get quiet hours: {:#}", e);
+            return;
+        }
+    }
+
+    let Some(res_message) = generate_reply(
+        config().default_target_user_id(),
+        Purpose::Random,
+        &payload.message.text,
+    )
+    .await
+    else {
+        info!("Failed to generate a message");
+        return;
+    };
+    let res = resource
+        .api
+        .post_message(channel_id, res_message, Some(&resource.rate_limiter))
+        .await;
     if let Err(e) = res {
         error!("Failed to post message: {}", e);
     }
 }
 
-pub async fn mentioned_handler(payload: payload::MessageCreated, resource: Resource) {
-    if payload.message.user.bot {
+/// 収集するユーザーのメッセージを保存し、markov chain に反映する
+pub async fn target_message_handler(payload: payload::MessageCreated) {
+    let message = payload.message;
+    if !config().target_user_ids.contains(&message.user.id) {
         return;
     }
 
-    if !is_mentioned_message(&payload.message, BOT_USER_ID) {
-        return;
+    let record = MessageRecord {
+        id: message.id,
+        user_id: message.user.id,
+        channel_id: message.channel_id,
+        content: message.text,
+        created_at: message.created_at.naive_utc(),
+    };
+    if let Err(e) = learn_streamed_message(POOL.get().unwrap(), &record).await {
+        error!("Failed to learn streamed message: {:#}", e);
     }
+}
 
-    if payload.message.plain_text.contains("join") {
-        let res = api::join_channel(payload.message.channel_id).await;
-        if let Err(e) = res {
-            error!("Failed to join channel: {}", e);
-        }
+/// 収集するユーザーのメッセージが編集されたら、保存しているメッセージと markov chain に反映する
+pub async fn message_updated_handler(payload: payload::MessageUpdated) {
+    let message = payload.message;
+    if !config().target_user_ids.contains(&message.user.id) {
         return;
     }
 
-    if payload.message.plain_text.contains("leave") {
-        let res = api::leave_channel(payload.message.channel_id).await;
-        if let Err(e) = res {
-            error!("Failed to leave channel: {}", e);
-        }
+    let change = MessageChange {
+        id: message.id,
+        content: Some(message.text),
+    };
+    if let Err(e) = apply_message_changes(POOL.get().unwrap(), &[change]).await {
+        error!("Failed to apply edited message: {:#}", e);
+    }
+}
+
+/// 保存しているメッセージが削除されたら、削除済みにして markov chain から取り除く
+pub async fn message_deleted_handler(payload: payload::MessageDeleted) {
+    let change = MessageChange {
+        id: payload.message.id,
+        content: None,
+    };
+    if let Err(e) = apply_message_changes(POOL.get().unwrap(), &[change]).await {
+        error!("Failed to apply deleted message: {:#}", e);
+    }
+}
+
+pub async fn mentioned_handler(payload: payload::MessageCreated, resource: Resource) {
+    if payload.message.user.bot {
         return;
     }
 
-    if handle_try_change_freq(&payload.message).await {
+    if !is_mentioned_message(&payload.message, &config().bot_user_id) {
         return;
     }
 
-    let res_message = generate_message();
-    let res = api::post_message(payload.message.channel_id, res_message, Some(&resource)).await;
-    if let Err(e) = res {
-        error!("Failed to post message: {}", e);
+    if command::dispatch(&COMMANDS, &resource, &payload.message, false).await {
+        return;
     }
-}
 
-static FREQ_COMMAND: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r"^\s*@(?:\w|[_-])+\s+(?:\\|/)freq\s+(\S+)\s*$").unwrap());
-pub async fn handle_try_change_freq(message: &traq_ws_bot::events::common::Message) -> bool {
-    let Some(capture) = FREQ_COMMAND.captures(&message.plain_text) else {
-        return false;
+    let Some(res_message) = generate_reply(
+        config().default_target_user_id(),
+        Purpose::Mention,
+        &payload.message.text,
+    )
+    .await
+    else {
+        info!("Failed to generate a message");
+        return;
     };
-
-    let freq = capture.get(1).unwrap().as_str();
-    let res_msg;
-    let changed_freq;
-
-    match freq {
-        "off" | "0" | "no" => {
-            let res = update_frequency(POOL.get().unwrap(), message.channel_id.clone(), 0).await;
-            match res {
-                Ok(_) => {
-                    changed_freq = Ok(0);
-                    res_msg = "返答をしないように設定しました :blob_pyon:".to_string();
-                }
-                Err(e) => {
-                    changed_freq = Err(());
-                    res_msg = "頻度の更新に失敗しました :Hyperblob:".to_string();
-                    error!("Failed to update frequency: {}", e);
-                }
-            }
-        }
-        "full" | "100" => {
-            let res = update_frequency(POOL.get().unwrap(), message.channel_id.clone(), 100).await;
-            match res {
-                Ok(_) => {
-                    changed_freq = Ok(100);
-                    res_msg = "常に返答をするように設定しました :blob_pyon:".to_string();
-                }
-                Err(e) => {
-                    changed_freq = Err(());
-                    res_msg = "頻度の更新に失敗しました :Hyperblob:".to_string();
-                    error!("Failed to update frequency: {}", e);
-                }
-            }
-        }
-        x if x.parse::<i64>().is_ok()
-            && x.parse::<i64>().unwrap() > 0
-            && x.parse::<i64>().unwrap() < 100 =>
-        {
-            let freq_int = x.parse::<i64>().unwrap();
-            let res =
-                update_frequency(POOL.get().unwrap(), message.channel_id.clone(), freq_int).await;
-            match res {
-                Ok(_) => {
-                    changed_freq = Ok(freq_int);
-                    res_msg = format!("頻度を {}% に設定しました :blob_pyon:", freq_int);
-                }
-                Err(e) => {
-                    changed_freq = Err(());
-                    res_msg = "頻度の更新に失敗しました :Hyperblob:".to_string();
-                    error!("Failed to update frequency: {}", e);
-                }
-            }
-        }
-        x if x.parse::<i64>().is_ok() => {
-            changed_freq = Err(());
-            res_msg = "不正な数値です :Hyperblob: (0~100 expected)".to_string();
-        }
-        _ => {
-            changed_freq = Err(());
-            res_msg = "不正な引数です :Hyperblob: (0~100 expected)".to_string();
-        }
-    }
-    if let Ok(freq) = changed_freq {
-        FREQUENCIES_CACHE
-            .lock()
-            .unwrap()
-            .insert(message.channel_id.clone(), freq);
-    }
-    let res = api::post_message(message.channel_id.clone(), res_msg, None).await;
+    let res = resource
+        .api
+        .post_message(
+            payload.message.channel_id,
+            res_message,
+            Some(&resource.rate_limiter),
+        )
+        .await;
     if let Err(e) = res {
         error!("Failed to post message: {}", e);
     }
-
-    true
 }
 
+/// channel_id の返信頻度 (設定されていなければ default_freq) を返す
 async fn get_frequency_with_cache(pool: &MySqlPool, channel_id: String) -> Option<i64> {
     let mut freq = FREQUENCIES_CACHE.lock().unwrap().get(&channel_id).copied();
     if freq.is_none() {
         freq = get_frequency(pool, channel_id.clone())
             .await
-            .map(|x| x.map(|r| r.frequency).unwrap_or(DEFAULT_FREQ))
+            .map(|x| x.map(|r| r.frequency).unwrap_or(config().default_freq))
             .ok();
         if let Some(freq) = freq {
             FREQUENCIES_CACHE
@@ -1,76 +1,107 @@
+mod command;
+mod config;
+mod constraints;
 mod cron;
+#[cfg(test)]
+mod fake_traq;
 mod handler;
+mod markov;
 mod messages;
 mod model;
+mod novelty;
+mod quiet;
+mod reload;
+mod role;
+mod server;
 mod utils;
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     env,
     sync::{Arc, Mutex},
     time::Duration,
 };
 
-use chrono::{DateTime, Local, NaiveDateTime};
+use anyhow::anyhow;
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
 use dotenv::dotenv;
 use lindera::tokenizer::Tokenizer;
-use markov::Chain;
 use once_cell::sync::{Lazy, OnceCell};
-use regex::{Regex, RegexSet};
-use rocket::futures::future;
+use rand::seq::SliceRandom;
+use regex::Regex;
 use sqlx::MySqlPool;
 
-use log::{debug, info};
+use log::{debug, error, info, warn};
 use traq_ws_bot::utils::RateLimiter;
 use utils::{split_all_regex, SplittedElement};
 
 use crate::{
-    cron::start_scheduling,
+    config::{config, Config},
+    constraints::Purpose,
+    cron::{start_scheduling, PostScheduler},
     handler::{
         direct_message_handler, join_handler, left_handler, mentioned_handler,
-        non_mentioned_message_handler,
+        message_deleted_handler, message_updated_handler, non_mentioned_message_handler,
+        target_message_handler,
     },
-    messages::{fetch_messages, get_latest_message, get_messages},
-    model::db::connect_db,
+    markov::BidirectionalChain,
+    messages::{
+        backfill_messages, fetch_all_messages_after, get_crawled_messages_after, get_messages,
+        get_unlearned_messages, save_streamed_message,
+    },
+    model::{
+        api::ApiClient,
+        db::{
+            connect_db, get_markov_cache, get_stored_messages, reset_learned_messages,
+            update_markov_cache, update_messages, MarkovCacheRecord, MessageChange, MessageRecord,
+            StoredMessageRecord,
+        },
+    },
+    novelty::NoveltyChecker,
+    quiet::QuietHours,
+    reload::reload_on_hangup,
+    role::warn_if_no_owner,
 };
 
-pub static MARKOV_CHAIN: Lazy<Mutex<Chain<String>>> = Lazy::new(|| Mutex::new(Chain::of_order(2)));
+/// 人格 (収集するユーザーの UUID) ごとの markov chain
+pub static MARKOV_CHAINS: Lazy<Mutex<HashMap<String, BidirectionalChain>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
 
-pub static FREQUENCIES_CACHE: Lazy<Mutex<HashMap<String, i64>>> =
+/// 人格 (収集するユーザーの UUID) ごとの丸写しの判定器
+pub static NOVELTY_CHECKERS: Lazy<Mutex<HashMap<String, NoveltyChecker>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
 
-/// 収集するユーザーの UUID
-pub const TARGET_USER_ID: &str = "81bbc211-65aa-4a45-8c56-e0b78d25f9e5";
+/// markov chain の更新や作り直しを同時に行わないためのロック
+static LEARNING_LOCK: Lazy<tokio::sync::Mutex<()>> = Lazy::new(|| tokio::sync::Mutex::new(()));
 
-/// この BOT の UUID
-pub const BOT_ID: &str = "32bbdf6e-8170-4987-ba20-71ecc589e4a6";
+/// websocket で受け取って markov chain に反映したが、まだ markov_cache に保存していないメッセージの ID (人格ごと)
+static UNSAVED_LEARNED_IDS: Lazy<Mutex<HashMap<String, Vec<String>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
 
-/// この BOT の USER ID
-pub const BOT_USER_ID: &str = "d8ff0b6c-431f-4476-9708-cb9d2e49b0a5";
+pub static FREQUENCIES_CACHE: Lazy<Mutex<HashMap<String, i64>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
 
-/// 定期投稿するチャンネルの UUID
-pub const CRON_CHANNEL_ID: &str = "11c32e27-5aa5-44f2-bc3b-ef8e94103ccf";
+/// チャンネルごとの投稿を控える時間帯 (設定されていなければ None)
+pub static QUIET_HOURS_CACHE: Lazy<Mutex<HashMap<String, Option<QuietHours>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
 
 pub static BOT_ACCESS_TOKEN: Lazy<String> = Lazy::new(|| {
     dotenv().ok();
     env::var("BOT_ACCESS_TOKEN").expect("BOT_ACCESS_TOKEN is not set")
 });
 
-/// この正規表現に一致するメッセージは、markov chain に反映されない
-pub static BLOCK_MESSAGE_REGEX: Lazy<RegexSet> = Lazy::new(|| {
-    RegexSet::new(vec![
-        r"^:awoo:$",
-        r"^(?:https?:)?//(?:\w|[.-])+/\S+\n*$",
-        r"^\[[^\[\]]+\]\((?:https?:)?//(?:\w|[.-])+/\S+\)\n*$",
-        r"^う+\n*$",
-        r"^%",
-    ])
-    .unwrap()
-});
-
 pub static POOL: OnceCell<MySqlPool> = OnceCell::new();
 
-pub type Resource = Arc<Arc<RateLimiter>>;
+/// handler と cron job で共有するもの
+#[derive(Clone)]
+pub struct BotResource {
+    pub api: ApiClient,
+    /// 返信と定期投稿の rate limit
+    pub rate_limiter: Arc<RateLimiter>,
+    pub scheduler: PostScheduler,
+}
+
+pub type Resource = Arc<BotResource>;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -78,33 +109,72 @@ async fn main() -> anyhow::Result<()> {
     env_logger::init();
     info!("Starting...");
 
+    config::init()?;
+
     let pool = connect_db().await?;
     POOL.set(pool).unwrap();
 
     debug!("db connected");
-    let rate_limiter = Arc::new(RateLimiter::new(5, Duration::from_secs(60)));
+    warn_if_no_owner(POOL.get().unwrap()).await?;
+    let rate_limit = config().rate_limit;
+    let api = ApiClient::new(&config().base_url, &config().bot_id, &BOT_ACCESS_TOKEN)?
+        .with_search_rate(config().crawl.requests_per_second);
+    let rate_limiter = Arc::new(RateLimiter::new(
+        rate_limit.max_count,
+        Duration::from_secs(rate_limit.interval_secs),
+    ));
+    let resource = BotResource {
+        scheduler: PostScheduler::new(api.clone(), rate_limiter.clone(), config().timezone),
+        api,
+        rate_limiter,
+    };
 
     let bot = traq_ws_bot::builder(&*BOT_ACCESS_TOKEN)
-        .insert_resource(rate_limiter.clone())
-        .on_joined(join_handler)
-        .on_left(left_handler)
-        .on_direct_message_created(direct_message_handler)
+        .insert_resource(resource.clone())
+        .on_joined_with_resource(join_handler)
+        .on_left_with_resource(left_handler)
+        .on_direct_message_created_with_resource(direct_message_handler)
         .on_message_created_with_resource(non_mentioned_message_handler)
         .on_message_created_with_resource(mentioned_handler)
+        .on_message_created(target_message_handler)
+        .on_message_updated(message_updated_handler)
+        .on_message_deleted(message_deleted_handler)
         .build();
 
     info!("loading markov chain cache...");
-    update_markov_chain(POOL.get().unwrap()).await?;
+    load_markov_chain(POOL.get().unwrap(), &resource.api).await?;
     info!("markov chain loaded successfully !");
 
-    let cron_loop = start_scheduling(POOL.get().unwrap(), CRON_CHANNEL_ID, rate_limiter).await?;
+    start_scheduling(
+        POOL.get().unwrap(),
+        config().cron_channel_id.clone(),
+        Arc::new(resource.clone()),
+    )
+    .await?;
 
-    let _ = future::join(bot.start(), cron_loop).await;
+    let api = resource.api.clone();
+    tokio::spawn(async move {
+        if let Err(e) = reload_on_hangup(POOL.get().unwrap(), &api).await {
+            error!("Failed to listen to SIGHUP: {:#}", e);
+        }
+    });
+    if let Some(port) = config().admin.http_port {
+        let token = env::var("ADMIN_TOKEN")
+            .map_err(|_| anyhow!("ADMIN_TOKEN is required to enable the admin http server"))?;
+        let api = resource.api.clone();
+        tokio::spawn(async move {
+            if let Err(e) = server::serve(POOL.get().unwrap(), api, port, token).await {
+                error!("{:#}", e);
+            }
+        });
+    }
+
+    let _ = bot.start().await;
 
     Ok(())
 }
 
-static STAMP_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r":@?(?:\w|[-.])+:").unwrap());
+pub static STAMP_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r":@?(?:\w|[-.])+:").unwrap());
 
 /// format
 ///
@@ -156,47 +226,579 @@ fn traq_message_format(messages: String) -> Vec<ContentType> {
     result
 }
 
-fn feed_messages(messages: &[String]) {
-    let tokenizer = Tokenizer::new().unwrap();
+static TOKENIZER: Lazy<Tokenizer> = Lazy::new(|| Tokenizer::new().unwrap());
+
+/// メッセージを markov chain の単語列に分割する
+fn tokenize_message(message: &str) -> Vec<String> {
+    traq_message_format(message.to_string())
+        .iter()
+        .flat_map(|e| match e {
+            ContentType::Text(text) => TOKENIZER.tokenize_str(text).unwrap(),
+            ContentType::Stamp(stamp) => vec![stamp.as_str()],
+            ContentType::SpecialLink(link) => vec![link.as_str()],
+        })
+        .map(String::from)
+        .collect()
+}
+
+/// 返信の起点に使えそうな単語 (名詞・動詞・形容詞) をメッセージから取り出す
+///
+/// スタンプやメンションは起点にしない
+fn extract_keywords(message: &str) -> Vec<String> {
+    traq_message_format(message.to_string())
+        .iter()
+        .flat_map(|e| match e {
+            ContentType::Text(text) => TOKENIZER.tokenize(text).unwrap(),
+            _ => vec![],
+        })
+        .filter(|token| {
+            let pos = token.detail.first().map(String::as_str);
+            let sub_pos = token.detail.get(1).map(String::as_str);
+            matches!(pos, Some("名詞" | "動詞" | "形容詞"))
+                && !matches!(sub_pos, Some("非自立" | "代名詞" | "数" | "接尾"))
+        })
+        .map(|token| token.text.to_string())
+        .collect()
+}
+
+/// user_id の人格の markov chain にメッセージを学習させる
+///
+/// user_id の人格が読み込まれていない場合は何もしない
+fn feed_messages(user_id: &str, messages: &[String]) {
     for message in messages {
-        if BLOCK_MESSAGE_REGEX.is_match(message) {
+        if config().block_message_regex.is_match(message) {
             continue;
         }
-        let message_elements = traq_message_format(message.to_string());
-        let tokens = message_elements
-            .iter()
-            .flat_map(|e| match e {
-                ContentType::Text(text) => tokenizer.tokenize_str(text).unwrap(),
-                ContentType::Stamp(stamp) => vec![stamp.as_str()],
-                ContentType::SpecialLink(link) => vec![link.as_str()],
-            })
-            .collect::<Vec<_>>();
+        let tokens = tokenize_message(message);
+        if let Some(chain) = MARKOV_CHAINS.lock().unwrap().get_mut(user_id) {
+            chain.feed(&tokens);
+        }
+        if let Some(checker) = NOVELTY_CHECKERS.lock().unwrap().get_mut(user_id) {
+            checker.insert(message.clone());
+        }
+    }
+}
 
-        let token = tokens.join(" ");
-        MARKOV_CHAIN.lock().unwrap().feed_str(&token);
+/// feed_messages で user_id の人格に学習させたメッセージを取り除く
+fn unfeed_message(user_id: &str, message: &str) {
+    if config().block_message_regex.is_match(message) {
+        return;
+    }
+    let tokens = tokenize_message(message);
+    if let Some(chain) = MARKOV_CHAINS.lock().unwrap().get_mut(user_id) {
+        chain.unfeed(&tokens);
+    }
+    if let Some(checker) = NOVELTY_CHECKERS.lock().unwrap().get_mut(user_id) {
+        checker.remove(message);
     }
 }
 
-fn generate_message() -> String {
-    MARKOV_CHAIN.lock().unwrap().generate().join("")
+/// generate で生成したメッセージのうち、purpose の制約を満たし、学習元のメッセージの丸写しでないものを返す
+///
+/// user_id の人格が存在しない場合や、制約で指定された回数だけ試行しても見つからない場合は None を返す
+fn generate_valid_message(
+    user_id: &str,
+    purpose: Purpose,
+    mut generate: impl FnMut(&BidirectionalChain) -> Option<Vec<String>>,
+) -> Option<String> {
+    let constraints = config().constraints[&purpose];
+    // 試行の間に学習や削除が待たされないよう、ロックは試行ごとに取り直す
+    (0..constraints.max_attempts).find_map(|_| {
+        let tokens = generate(MARKOV_CHAINS.lock().unwrap().get(user_id)?)?;
+        if !constraints.is_satisfied_by(&tokens) {
+            return None;
+        }
+        let message = tokens.join("");
+        NOVELTY_CHECKERS
+            .lock()
+            .unwrap()
+            .get(user_id)?
+            .is_novel(&message)
+            .then_some(message)
+    })
 }
 
-pub async fn update_markov_chain(pool: &MySqlPool) -> anyhow::Result<()> {
-    let after = get_latest_message(pool)
-        .await?
-        .map(|m| naive_to_local(m.created_at));
+fn generate_random_message(user_id: &str, purpose: Purpose) -> Option<String> {
+    generate_valid_message(user_id, purpose, |chain| {
+        Some(chain.generate(config().markov.backoff_threshold))
+    })
+}
+
+/// message に含まれる単語のいずれかを含むメッセージを生成する
+///
+/// 学習済みの単語が含まれていない場合は None を返す
+fn generate_message_with_keyword(user_id: &str, purpose: Purpose, message: &str) -> Option<String> {
+    let keywords = extract_keywords(message);
+    generate_valid_message(user_id, purpose, |chain| {
+        let keyword = keywords.choose(&mut rand::thread_rng())?;
+        chain.generate_around_token(keyword, config().markov.backoff_threshold)
+    })
+}
+
+/// user_id の人格でメッセージを生成する
+async fn generate_message(user_id: &str, purpose: Purpose) -> Option<String> {
+    let user_id = user_id.to_string();
+    spawn_generation(move || generate_random_message(&user_id, purpose)).await
+}
+
+/// message への返信を生成する
+///
+/// keyword_reply が有効な場合は message に含まれる単語を含むメッセージを優先する
+async fn generate_reply(user_id: &str, purpose: Purpose, message: &str) -> Option<String> {
+    let (user_id, message) = (user_id.to_string(), message.to_string());
+    spawn_generation(move || {
+        if config().keyword_reply {
+            if let Some(reply) = generate_message_with_keyword(&user_id, purpose, &message) {
+                return Some(reply);
+            }
+        }
+        generate_random_message(&user_id, purpose)
+    })
+    .await
+}
+
+/// 生成は学習元のメッセージを何度も走査して重いので、async の worker を塞がないよう blocking なスレッドで行う
+async fn spawn_generation(
+    generate: impl FnOnce() -> Option<String> + Send + 'static,
+) -> Option<String> {
+    tokio::task::spawn_blocking(generate)
+        .await
+        .unwrap_or_else(|e| {
+            error!("Failed to generate a message: {}", e);
+            None
+        })
+}
+
+/// 設定された次数で空の markov chain を作成する
+fn new_markov_chain() -> BidirectionalChain {
+    let (min_order, max_order) = config().markov.orders();
+    BidirectionalChain::new(min_order, max_order)
+}
+
+fn new_novelty_checker() -> NoveltyChecker {
+    let novelty = config().novelty;
+    NoveltyChecker::new(novelty.min_edit_distance, novelty.max_overlap_ratio)
+}
+
+/// すべての人格について、markov_cache から markov chain を復元し、まだ反映されていないメッセージを反映する
+async fn load_markov_chain(pool: &MySqlPool, api: &ApiClient) -> anyhow::Result<()> {
+    for user_id in &config().target_user_ids {
+        restore_markov_chain(pool, user_id).await?;
+        load_novelty_checker(pool, user_id).await?;
+    }
+    update_markov_chain(pool, api).await
+}
+
+/// 丸写しの判定に用いる user_id のメッセージを DB から読み込む
+async fn load_novelty_checker(pool: &MySqlPool, user_id: &str) -> anyhow::Result<()> {
+    let messages = get_messages(pool, user_id).await?;
+    let mut checker = new_novelty_checker();
+    for message in messages {
+        if !config().block_message_regex.is_match(&message.content) {
+            checker.insert(message.content);
+        }
+    }
+    NOVELTY_CHECKERS
+        .lock()
+        .unwrap()
+        .insert(user_id.to_string(), checker);
+    Ok(())
+}
+
+/// markov_cache に保存されている user_id の markov chain で MARKOV_CHAINS を置き換える
+///
+/// キャッシュが存在しない場合や、キャッシュの次数が設定と異なる場合は、
+/// 空の markov chain にして user_id のすべてのメッセージを未反映に戻す
+async fn restore_markov_chain(pool: &MySqlPool, user_id: &str) -> anyhow::Result<()> {
+    let cache = get_markov_cache(pool, user_id).await?;
+    let chain = cache.and_then(|cache| {
+        info!(
+            "markov chain cache for {} found (last update: {})",
+            user_id, cache.last_update
+        );
+        match serde_yaml::from_str::<BidirectionalChain>(&cache.cache) {
+            Ok(chain) if chain.orders() == config().markov.orders() => Some(chain),
+            Ok(chain) => {
+                info!(
+                    "markov order changed from {:?}, rebuilding markov chain...",
+                    chain.orders()
+                );
+                None
+            }
+            Err(e) => {
+                error!("failed to parse markov chain cache, rebuilding: {}", e);
+                None
+            }
+        }
+    });
+    let chain = match chain {
+        Some(chain) => chain,
+        None => {
+            reset_learned_messages(pool, user_id).await?;
+            new_markov_chain()
+        }
+    };
+    MARKOV_CHAINS
+        .lock()
+        .unwrap()
+        .insert(user_id.to_string(), chain);
+    UNSAVED_LEARNED_IDS.lock().unwrap().remove(user_id);
+    Ok(())
+}
+
+/// すべての人格について、新しいメッセージを取得し、まだ反映されていないメッセージのみを markov chain に反映して保存する
+pub async fn update_markov_chain(pool: &MySqlPool, api: &ApiClient) -> anyhow::Result<()> {
+    let _guard = LEARNING_LOCK.lock().await;
+    for user_id in &config().target_user_ids {
+        update_markov_chain_of(pool, api, user_id).await?;
+    }
+    Ok(())
+}
+
+/// user_id の新しいメッセージを取得し、まだ反映されていないメッセージのみを markov chain に反映して保存する
+async fn update_markov_chain_of(
+    pool: &MySqlPool,
+    api: &ApiClient,
+    user_id: &str,
+) -> anyhow::Result<()> {
     let force_fetch = env::var("FORCE_FETCH").map(|v| v == "1").unwrap_or(false);
-    fetch_messages(pool, None, if force_fetch { None } else { after }).await?;
-    let messages = get_messages(pool).await?;
+    backfill_messages(pool, api, user_id, force_fetch).await?;
+
+    // websocket で受け取ったメッセージは既に反映されているので、保存だけする
+    let unsaved_ids = UNSAVED_LEARNED_IDS
+        .lock()
+        .unwrap()
+        .remove(user_id)
+        .unwrap_or_default();
+    let messages = get_unlearned_messages(pool, user_id)
+        .await?
+        .into_iter()
+        .filter(|m| !unsaved_ids.contains(&m.id))
+        .collect::<Vec<_>>();
+    if messages.is_empty() && unsaved_ids.is_empty() {
+        return Ok(());
+    }
     feed_messages(
+        user_id,
         &messages
             .iter()
             .map(|m| m.content.clone())
             .collect::<Vec<String>>(),
     );
+
+    let learned_ids = messages
+        .iter()
+        .map(|m| m.id.clone())
+        .chain(unsaved_ids)
+        .collect::<Vec<_>>();
+    if let Err(e) = save_markov_chain(pool, user_id, &learned_ids).await {
+        // 反映済みとして保存できなかったメッセージは次回も反映されるため、二重に数えないようにキャッシュの状態へ戻す
+        restore_markov_chain(pool, user_id).await?;
+        return Err(e);
+    }
+    debug!(
+        "{} messages fed into markov chain of {}",
+        messages.len(),
+        user_id
+    );
+    Ok(())
+}
+
+/// websocket で受け取った、収集するユーザーのメッセージを保存し、すぐに markov chain に反映する
+///
+/// markov_cache への保存は次の update_markov_chain でまとめて行う
+pub async fn learn_streamed_message(
+    pool: &MySqlPool,
+    message: &MessageRecord,
+) -> anyhow::Result<()> {
+    let _guard = LEARNING_LOCK.lock().await;
+    if !save_streamed_message(pool, message).await? {
+        return Ok(());
+    }
+    feed_messages(&message.user_id, std::slice::from_ref(&message.content));
+    UNSAVED_LEARNED_IDS
+        .lock()
+        .unwrap()
+        .entry(message.user_id.clone())
+        .or_default()
+        .push(message.id.clone());
+    debug!("streamed message {} fed into markov chain", message.id);
+    Ok(())
+}
+
+/// user_id のすべてのメッセージから markov chain を作り直して保存する
+///
+/// 保存に失敗した場合は、MARKOV_CHAINS を変更しない
+async fn rebuild_markov_chain(pool: &MySqlPool, user_id: &str) -> anyhow::Result<()> {
+    let messages = get_messages(pool, user_id).await?;
+    let contents = messages
+        .iter()
+        .map(|m| m.content.clone())
+        .collect::<Vec<_>>();
+    // 全メッセージの形態素解析は重いので、async の worker を塞がないよう blocking なスレッドで行う
+    let owner = user_id.to_string();
+    let (chain, record) = tokio::task::spawn_blocking(move || {
+        let block_message_regex = &config().block_message_regex;
+        let mut chain = new_markov_chain();
+        for content in &contents {
+            if !block_message_regex.is_match(content) {
+                chain.feed(&tokenize_message(content));
+            }
+        }
+        let record = markov_cache_record(&owner, &chain)?;
+        anyhow::Ok((chain, record))
+    })
+    .await??;
+
+    let learned_ids = messages.iter().map(|m| m.id.clone()).collect::<Vec<_>>();
+    update_markov_cache(pool, &record, &learned_ids).await?;
+    MARKOV_CHAINS
+        .lock()
+        .unwrap()
+        .insert(user_id.to_string(), chain);
+    UNSAVED_LEARNED_IDS.lock().unwrap().remove(user_id);
+    info!(
+        "markov chain of {} rebuilt from {} messages",
+        user_id,
+        messages.len()
+    );
+    Ok(())
+}
+
+/// 再読み込みされた設定の変更を、markov chain と丸写しの判定器に反映する
+///
+/// 学習しないメッセージの条件や次数が変わった場合は DB のメッセージから作り直し、
+/// 追加された人格はキャッシュから復元する
+pub async fn apply_config_changes(
+    pool: &MySqlPool,
+    api: &ApiClient,
+    old: &Config,
+    new: &Config,
+) -> anyhow::Result<()> {
+    let _guard = LEARNING_LOCK.lock().await;
+    MARKOV_CHAINS
+        .lock()
+        .unwrap()
+        .retain(|user_id, _| new.target_user_ids.contains(user_id));
+    NOVELTY_CHECKERS
+        .lock()
+        .unwrap()
+        .retain(|user_id, _| new.target_user_ids.contains(user_id));
+
+    let rebuild = old.requires_rebuild(new);
+    for user_id in &new.target_user_ids {
+        if rebuild {
+            rebuild_markov_chain(pool, user_id).await?;
+            load_novelty_checker(pool, user_id).await?;
+        } else if !old.target_user_ids.contains(user_id) {
+            restore_markov_chain(pool, user_id).await?;
+            load_novelty_checker(pool, user_id).await?;
+            update_markov_chain_of(pool, api, user_id).await?;
+        } else if old.novelty != new.novelty {
+            load_novelty_checker(pool, user_id).await?;
+        }
+    }
+    Ok(())
+}
+
+/// user_id の現在の markov chain を保存し、learned_ids のメッセージを反映済みとする
+async fn save_markov_chain(
+    pool: &MySqlPool,
+    user_id: &str,
+    learned_ids: &[String],
+) -> anyhow::Result<()> {
+    let record = {
+        let chains = MARKOV_CHAINS.lock().unwrap();
+        let chain = chains
+            .get(user_id)
+            .ok_or_else(|| anyhow!("markov chain of {} is not loaded", user_id))?;
+        markov_cache_record(user_id, chain)?
+    };
+    update_markov_cache(pool, &record, learned_ids).await?;
+    Ok(())
+}
+
+/// user_id の markov chain を markov_cache に保存する形式にする
+fn markov_cache_record(
+    user_id: &str,
+    chain: &BidirectionalChain,
+) -> anyhow::Result<MarkovCacheRecord> {
+    let cache = serde_yaml::to_string(chain)?;
+    Ok(MarkovCacheRecord {
+        user_id: user_id.to_string(),
+        cache,
+        last_update: Utc::now().naive_utc(),
+    })
+}
+
+/// 編集・削除されたメッセージを DB に保存し、markov chain に反映済みであれば古い内容を取り除いて新しい内容を反映する
+///
+/// 保存されていないメッセージの変更は無視する
+pub async fn apply_message_changes(
+    pool: &MySqlPool,
+    changes: &[MessageChange],
+) -> anyhow::Result<()> {
+    let _guard = LEARNING_LOCK.lock().await;
+    let ids = changes.iter().map(|c| c.id.clone()).collect::<Vec<_>>();
+    let stored = get_stored_messages(pool, &ids)
+        .await?
+        .into_iter()
+        .map(|m| (m.message.id.clone(), m))
+        .collect::<HashMap<_, _>>();
+    let changes = changes
+        .iter()
+        .filter(|c| stored.contains_key(&c.id))
+        .cloned()
+        .collect::<Vec<_>>();
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    let changed_user_ids = apply_changes_to_chains(&changes, &stored);
+    let caches = changed_user_ids
+        .iter()
+        .filter_map(|user_id| {
+            let unsaved_ids = UNSAVED_LEARNED_IDS
+                .lock()
+                .unwrap()
+                .remove(user_id)
+                .unwrap_or_default();
+            let chains = MARKOV_CHAINS.lock().unwrap();
+            let record = markov_cache_record(user_id, chains.get(user_id)?);
+            Some(record.map(|record| (record, unsaved_ids)))
+        })
+        .collect::<anyhow::Result<Vec<_>>>();
+    let res = match caches {
+        Ok(caches) => update_messages(pool, &changes, &caches).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = res {
+        // 保存できなかった変更は markov chain からも取り消す
+        for user_id in &changed_user_ids {
+            restore_markov_chain(pool, user_id).await?;
+            load_novelty_checker(pool, user_id).await?;
+        }
+        return Err(e);
+    }
+    info!("{} edited or deleted messages applied", changes.len());
     Ok(())
 }
 
+/// 編集・削除されたメッセージのうち markov chain に反映済みのものについて、古い内容を取り除いて新しい内容を反映し、
+/// markov chain が変わった人格を返す
+///
+/// 設定から外された人格など、markov chain が読み込まれていない人格のメッセージは無視する
+fn apply_changes_to_chains(
+    changes: &[MessageChange],
+    stored: &HashMap<String, StoredMessageRecord>,
+) -> HashSet<String> {
+    let mut changed_user_ids = HashSet::new();
+    for change in changes {
+        let stored = &stored[&change.id];
+        let user_id = &stored.message.user_id;
+        if !MARKOV_CHAINS.lock().unwrap().contains_key(user_id) {
+            continue;
+        }
+        let in_chain = stored.learned
+            || UNSAVED_LEARNED_IDS
+                .lock()
+                .unwrap()
+                .get(user_id)
+                .is_some_and(|ids| ids.contains(&change.id));
+        if !in_chain {
+            continue;
+        }
+        unfeed_message(user_id, &stored.message.content);
+        if let Some(content) = &change.content {
+            feed_messages(user_id, std::slice::from_ref(content));
+        }
+        changed_user_ids.insert(user_id.clone());
+    }
+    changed_user_ids
+}
+
+/// すべての人格について、直近 reconcile_days 日間のメッセージを API の検索結果と照らし合わせ、編集・削除を反映する
+pub async fn reconcile_messages(pool: &MySqlPool, api: &ApiClient) -> anyhow::Result<()> {
+    for user_id in &config().target_user_ids {
+        reconcile_messages_of(pool, api, user_id).await?;
+    }
+    Ok(())
+}
+
+async fn reconcile_messages_of(
+    pool: &MySqlPool,
+    api: &ApiClient,
+    user_id: &str,
+) -> anyhow::Result<()> {
+    let started_at = Utc::now().naive_utc();
+    let after = started_at - chrono::Duration::days(config().reconcile_days);
+    let Some(fetched) = fetch_all_messages_after(api, user_id, &naive_to_local(after)).await?
+    else {
+        // 一部しか取得できないと、取得できなかったメッセージを削除されたとみなしてしまう
+        warn!(
+            "too many messages of {} to reconcile, skipping reconciliation",
+            user_id
+        );
+        return Ok(());
+    };
+    let fetched = fetched
+        .into_iter()
+        .map(|m| (m.id, m.content))
+        .collect::<HashMap<_, _>>();
+
+    // 取得を始めた後に投稿されたメッセージは、検索結果に含まれないことがある
+    let changes = get_crawled_messages_after(pool, user_id, after)
+        .await?
+        .into_iter()
+        .filter(|m| m.created_at < started_at)
+        .filter_map(|m| match fetched.get(&m.id) {
+            Some(content) if *content == m.content => None,
+            content => Some(MessageChange {
+                id: m.id,
+                content: content.cloned(),
+            }),
+        })
+        .collect::<Vec<_>>();
+    if changes.is_empty() {
+        return Ok(());
+    }
+    apply_message_changes(pool, &changes).await
+}
+
 fn naive_to_local(naive: NaiveDateTime) -> DateTime<Local> {
-    DateTime::<Local>::from_utc(naive, *Local::now().offset())
+    Local.from_utc_datetime(&naive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_changes_to_chains_of_removed_persona() {
+        let stored = StoredMessageRecord {
+            message: MessageRecord {
+                id: "message".to_string(),
+                user_id: "removed-persona".to_string(),
+                channel_id: "channel".to_string(),
+                content: "こんにちは".to_string(),
+                created_at: Utc::now().naive_utc(),
+            },
+            learned: true,
+        };
+        let stored = HashMap::from([(stored.message.id.clone(), stored)]);
+        let changes = [MessageChange {
+            id: "message".to_string(),
+            content: None,
+        }];
+
+        assert!(apply_changes_to_chains(&changes, &stored).is_empty());
+        assert!(!MARKOV_CHAINS
+            .lock()
+            .unwrap()
+            .contains_key("removed-persona"));
+        assert!(!NOVELTY_CHECKERS
+            .lock()
+            .unwrap()
+            .contains_key("removed-persona"));
+    }
 }
@@ -0,0 +1,396 @@
+use std::collections::{HashMap, HashSet};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// 文の始まりと終わりは None で表す
+type Token = Option<String>;
+
+/// 複数の次数をまとめて学習する markov chain
+///
+/// 生成時は、直前の文脈が threshold 回以上出現している最大の次数で次の単語を選び、
+/// 出現回数が少ない文脈では低い次数にフォールバックする
+/// (min_order と max_order が等しいときは通常の markov chain と同じ)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "ChainData")]
+pub struct Chain {
+    min_order: usize,
+    max_order: usize,
+    /// 長さ min_order ~ max_order の文脈から、次の単語とその出現回数への map
+    map: HashMap<Vec<Token>, HashMap<Token, usize>>,
+    /// 単語から、その単語で終わる長さ max_order の文脈への索引 (map から作れるので保存しない)
+    #[serde(skip)]
+    contexts_by_last: HashMap<String, HashSet<Vec<Token>>>,
+}
+
+/// 保存された Chain の形式 (索引は読み込み時に作り直す)
+#[derive(Deserialize)]
+struct ChainData {
+    min_order: usize,
+    max_order: usize,
+    map: HashMap<Vec<Token>, HashMap<Token, usize>>,
+}
+
+impl From<ChainData> for Chain {
+    fn from(data: ChainData) -> Self {
+        let mut contexts_by_last = HashMap::<String, HashSet<Vec<Token>>>::new();
+        for context in data.map.keys().filter(|c| c.len() == data.max_order) {
+            if let Some(Some(last)) = context.last() {
+                contexts_by_last
+                    .entry(last.clone())
+                    .or_default()
+                    .insert(context.clone());
+            }
+        }
+        Self {
+            min_order: data.min_order,
+            max_order: data.max_order,
+            map: data.map,
+            contexts_by_last,
+        }
+    }
+}
+
+impl Chain {
+    /// min_order 以上 max_order 以下の次数を持つ markov chain を作成する
+    pub fn new(min_order: usize, max_order: usize) -> Self {
+        assert!(
+            0 < min_order && min_order <= max_order,
+            "invalid markov order: {}..={}",
+            min_order,
+            max_order
+        );
+        Self {
+            min_order,
+            max_order,
+            map: HashMap::new(),
+            contexts_by_last: HashMap::new(),
+        }
+    }
+
+    /// (min_order, max_order) を返す
+    pub fn orders(&self) -> (usize, usize) {
+        (self.min_order, self.max_order)
+    }
+
+    /// 1 文分の単語列を学習させる
+    pub fn feed(&mut self, tokens: &[String]) {
+        if tokens.is_empty() {
+            return;
+        }
+        let mut toks = vec![None; self.max_order];
+        toks.extend(tokens.iter().cloned().map(Some));
+        toks.push(None);
+
+        for i in self.max_order..toks.len() {
+            for order in self.min_order..=self.max_order {
+                *self
+                    .map
+                    .entry(toks[i - order..i].to_vec())
+                    .or_default()
+                    .entry(toks[i].clone())
+                    .or_default() += 1;
+            }
+            if let Some(last) = &toks[i - 1] {
+                self.contexts_by_last
+                    .entry(last.clone())
+                    .or_default()
+                    .insert(toks[i - self.max_order..i].to_vec());
+            }
+        }
+    }
+
+    /// feed で学習させた 1 文分の単語列を取り除く
+    pub fn unfeed(&mut self, tokens: &[String]) {
+        if tokens.is_empty() {
+            return;
+        }
+        let mut toks = vec![None; self.max_order];
+        toks.extend(tokens.iter().cloned().map(Some));
+        toks.push(None);
+
+        for i in self.max_order..toks.len() {
+            for order in self.min_order..=self.max_order {
+                let context = &toks[i - order..i];
+                let Some(states) = self.map.get_mut(context) else {
+                    continue;
+                };
+                if let Some(count) = states.get_mut(&toks[i]) {
+                    *count -= 1;
+                    if *count == 0 {
+                        states.remove(&toks[i]);
+                    }
+                }
+                if states.is_empty() {
+                    self.map.remove(context);
+                    if order == self.max_order {
+                        self.remove_indexed_context(context);
+                    }
+                }
+            }
+        }
+    }
+
+    /// 取り除かれた長さ max_order の文脈を索引からも取り除く
+    fn remove_indexed_context(&mut self, context: &[Token]) {
+        let Some(Some(last)) = context.last() else {
+            return;
+        };
+        if let Some(contexts) = self.contexts_by_last.get_mut(last) {
+            contexts.remove(context);
+            if contexts.is_empty() {
+                self.contexts_by_last.remove(last);
+            }
+        }
+    }
+
+    /// 文を 1 つ生成する
+    ///
+    /// 文脈の出現回数が threshold 未満のときは、低い次数の文脈を用いる
+    pub fn generate(&self, threshold: usize) -> Vec<String> {
+        self.generate_from_history(vec![None; self.max_order], threshold)
+    }
+
+    /// token から始まる文を生成する (token を学習していない場合は None を返す)
+    ///
+    /// token で終わる文脈を出現回数に比例した確率で選び、そこから続きを生成する
+    pub fn generate_from_token(&self, token: &str, threshold: usize) -> Option<Vec<String>> {
+        let contexts = self
+            .contexts_by_last
+            .get(token)?
+            .iter()
+            .map(|context| (context, self.map[context].values().sum::<usize>()));
+        let history = choose_weighted(contexts)?.clone();
+
+        let mut result = vec![token.to_string()];
+        result.extend(self.generate_from_history(history, threshold));
+        Some(result)
+    }
+
+    /// 長さ max_order の history に続く単語列を生成する
+    fn generate_from_history(&self, mut history: Vec<Token>, threshold: usize) -> Vec<String> {
+        let mut result = Vec::new();
+        while let Some(Some(next)) = self.next_token(&history, threshold) {
+            history.remove(0);
+            history.push(Some(next.clone()));
+            result.push(next);
+        }
+        result
+    }
+
+    /// history に続く単語を選ぶ (どの次数の文脈も未知の場合は None を返す)
+    fn next_token(&self, history: &[Token], threshold: usize) -> Option<Token> {
+        let mut fallback = None;
+        for order in (self.min_order..=self.max_order).rev() {
+            let Some(states) = self.map.get(&history[history.len() - order..]) else {
+                continue;
+            };
+            if states.values().sum::<usize>() >= threshold {
+                return Some(choose(states));
+            }
+            fallback = Some(states);
+        }
+        fallback.map(choose)
+    }
+}
+
+/// 順方向と逆方向の markov chain の組
+///
+/// 逆方向の chain は単語列を逆順にしたものを学習しており、ある単語より左側を生成するのに用いる
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BidirectionalChain {
+    forward: Chain,
+    backward: Chain,
+}
+
+impl BidirectionalChain {
+    /// min_order 以上 max_order 以下の次数を持つ markov chain の組を作成する
+    pub fn new(min_order: usize, max_order: usize) -> Self {
+        Self {
+            forward: Chain::new(min_order, max_order),
+            backward: Chain::new(min_order, max_order),
+        }
+    }
+
+    /// (min_order, max_order) を返す
+    pub fn orders(&self) -> (usize, usize) {
+        self.forward.orders()
+    }
+
+    /// 1 文分の単語列を順方向と逆方向の両方に学習させる
+    pub fn feed(&mut self, tokens: &[String]) {
+        self.forward.feed(tokens);
+        let reversed = tokens.iter().rev().cloned().collect::<Vec<_>>();
+        self.backward.feed(&reversed);
+    }
+
+    /// feed で学習させた 1 文分の単語列を順方向と逆方向の両方から取り除く
+    pub fn unfeed(&mut self, tokens: &[String]) {
+        self.forward.unfeed(tokens);
+        let reversed = tokens.iter().rev().cloned().collect::<Vec<_>>();
+        self.backward.unfeed(&reversed);
+    }
+
+    /// 文を 1 つ生成する
+    pub fn generate(&self, threshold: usize) -> Vec<String> {
+        self.forward.generate(threshold)
+    }
+
+    /// token を含む文を生成する (token を学習していない場合は None を返す)
+    ///
+    /// 逆方向の chain で token より左側を文頭まで生成し、その文脈から順方向の chain で右側を生成する
+    pub fn generate_around_token(&self, token: &str, threshold: usize) -> Option<Vec<String>> {
+        let mut result = self.backward.generate_from_token(token, threshold)?;
+        result.reverse();
+
+        let max_order = self.forward.max_order;
+        let mut history = vec![None; max_order];
+        history.extend(result.iter().cloned().map(Some));
+        let history = history.split_off(history.len() - max_order);
+
+        result.extend(self.forward.generate_from_history(history, threshold));
+        Some(result)
+    }
+}
+
+/// 出現回数に比例した確率で次の単語を選ぶ
+fn choose(states: &HashMap<Token, usize>) -> Token {
+    choose_weighted(states.iter().map(|(token, &count)| (token, count)))
+        .expect("states must not be empty")
+        .clone()
+}
+
+/// 重みに比例した確率で要素を選ぶ (重みの合計が 0 の場合は None を返す)
+fn choose_weighted<T>(items: impl IntoIterator<Item = (T, usize)>) -> Option<T> {
+    let items = items.into_iter().collect::<Vec<_>>();
+    let total = items.iter().map(|(_, weight)| weight).sum::<usize>();
+    if total == 0 {
+        return None;
+    }
+    let mut cap = rand::thread_rng().gen_range(0..total);
+    for (item, weight) in items {
+        if cap < weight {
+            return Some(item);
+        }
+        cap -= weight;
+    }
+    unreachable!("total weight is inconsistent")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(s: &str) -> Vec<String> {
+        s.split(' ').map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn test_generate_empty() {
+        let chain = Chain::new(2, 2);
+        assert!(chain.generate(1).is_empty());
+    }
+
+    #[test]
+    fn test_generate_single_sentence() {
+        let mut chain = Chain::new(2, 2);
+        chain.feed(&tokens("a b c d"));
+        assert_eq!(chain.generate(1), tokens("a b c d"));
+    }
+
+    #[test]
+    fn test_generate_from_token() {
+        let mut chain = Chain::new(2, 2);
+        chain.feed(&tokens("a b c d"));
+        assert_eq!(chain.generate_from_token("c", 1), Some(tokens("c d")));
+        assert_eq!(chain.generate_from_token("a", 1), Some(tokens("a b c d")));
+        assert_eq!(chain.generate_from_token("x", 1), None);
+    }
+
+    #[test]
+    fn test_generate_around_token() {
+        let mut chain = BidirectionalChain::new(2, 2);
+        chain.feed(&tokens("a b c d"));
+        assert_eq!(chain.generate_around_token("c", 1), Some(tokens("a b c d")));
+        assert_eq!(chain.generate_around_token("x", 1), None);
+    }
+
+    #[test]
+    fn test_generate_around_token_keeps_token() {
+        let mut chain = BidirectionalChain::new(1, 2);
+        chain.feed(&tokens("x a b"));
+        chain.feed(&tokens("y a c"));
+        chain.feed(&tokens("z d"));
+        for _ in 0..20 {
+            let generated = chain.generate_around_token("a", 1).unwrap();
+            assert!(generated.contains(&"a".to_string()));
+            assert!(!generated.contains(&"d".to_string()));
+        }
+    }
+
+    #[test]
+    fn test_feed_counts_every_order() {
+        let mut chain = Chain::new(1, 3);
+        chain.feed(&tokens("a b"));
+        chain.feed(&tokens("a c"));
+        assert_eq!(chain.map[&vec![None]][&Some("a".to_string())], 2);
+        assert_eq!(
+            chain.map[&vec![None, None, None]][&Some("a".to_string())],
+            2
+        );
+        assert_eq!(chain.map[&vec![Some("a".to_string())]].len(), 2);
+        assert_eq!(chain.map[&vec![None, None, Some("a".to_string())]].len(), 2);
+    }
+
+    #[test]
+    fn test_unfeed() {
+        let mut chain = BidirectionalChain::new(1, 2);
+        chain.feed(&tokens("a b c"));
+        let expected = chain.clone();
+        chain.feed(&tokens("a d"));
+        chain.feed(&tokens("x b"));
+        chain.unfeed(&tokens("a d"));
+        chain.unfeed(&tokens("x b"));
+        assert_eq!(chain, expected);
+
+        chain.unfeed(&tokens("a b c"));
+        assert_eq!(chain, BidirectionalChain::new(1, 2));
+    }
+
+    #[test]
+    fn test_index_survives_serialization() {
+        let mut chain = Chain::new(1, 2);
+        chain.feed(&tokens("a b c"));
+        chain.feed(&tokens("x b d"));
+        chain.unfeed(&tokens("x b d"));
+        let restored =
+            serde_yaml::from_str::<Chain>(&serde_yaml::to_string(&chain).unwrap()).unwrap();
+        assert_eq!(restored, chain);
+        assert_eq!(restored.contexts_by_last["b"].len(), 1);
+        assert!(!restored.contexts_by_last.contains_key("x"));
+    }
+
+    #[test]
+    fn test_generate_uses_high_order_when_frequent() {
+        let mut chain = Chain::new(1, 2);
+        chain.feed(&tokens("x a b"));
+        chain.feed(&tokens("y a c"));
+        // 文脈 [x, a] は 1 回しか出現していないが、threshold が 1 なら次数 2 を用いる
+        for _ in 0..20 {
+            let generated = chain.generate(1);
+            assert!(generated == tokens("x a b") || generated == tokens("y a c"));
+        }
+    }
+
+    #[test]
+    fn test_generate_backs_off_when_rare() {
+        let mut chain = Chain::new(1, 2);
+        chain.feed(&tokens("x a b"));
+        chain.feed(&tokens("y a c"));
+        // threshold が大きいと次数 1 にフォールバックし、x a c のような文も生成される
+        let generated = (0..200).map(|_| chain.generate(100)).collect::<Vec<_>>();
+        assert!(generated
+            .iter()
+            .any(|g| *g == tokens("x a c") || *g == tokens("y a b")));
+    }
+}
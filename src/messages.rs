@@ -1,143 +1,444 @@
-use chrono::{DateTime, Local, TimeZone};
+use std::future::Future;
+
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Timelike, Utc};
+use log::debug;
 use sqlx::MySqlPool;
 
-use crate::{
-    model::{
-        api,
-        db::{self, MessageRecord},
-    },
-    naive_to_local,
+use crate::model::{
+    api::ApiClient,
+    db::{self, FetchWindowRecord, MessageRecord},
 };
 
-pub async fn get_messages(pool: &MySqlPool) -> anyhow::Result<Vec<MessageRecord>> {
-    let messages = db::get_messages(pool).await?;
+/// traQ の検索で取得できる件数の上限
+const SEARCH_HITS_LIMIT: usize = 10000;
+
+pub async fn get_messages(pool: &MySqlPool, user_id: &str) -> anyhow::Result<Vec<MessageRecord>> {
+    let messages = db::get_messages(pool, user_id).await?;
+    Ok(messages)
+}
+
+pub async fn get_unlearned_messages(
+    pool: &MySqlPool,
+    user_id: &str,
+) -> anyhow::Result<Vec<MessageRecord>> {
+    let messages = db::get_unlearned_messages(pool, user_id).await?;
     Ok(messages)
 }
 
-pub async fn get_latest_message(pool: &MySqlPool) -> anyhow::Result<Option<MessageRecord>> {
-    let message = db::get_latest_message(pool).await?;
+pub async fn get_latest_message(
+    pool: &MySqlPool,
+    user_id: &str,
+) -> anyhow::Result<Option<MessageRecord>> {
+    let message = db::get_latest_message(pool, user_id).await?;
     Ok(message)
 }
 
-/// after から before の期間のメッセージを API から取得し、DB に保存する
+/// API から取得した user_id のメッセージのうち、after 以降に投稿された、削除されていないものを取得する
+pub async fn get_crawled_messages_after(
+    pool: &MySqlPool,
+    user_id: &str,
+    after: NaiveDateTime,
+) -> anyhow::Result<Vec<MessageRecord>> {
+    let messages = db::get_crawled_messages_after(pool, user_id, after).await?;
+    Ok(messages)
+}
+
+/// websocket で受け取ったメッセージを DB に保存する
+///
+/// 既に保存されていた場合は false を返す
+pub async fn save_streamed_message(
+    pool: &MySqlPool,
+    message: &MessageRecord,
+) -> anyhow::Result<bool> {
+    let inserted = db::insert_messages(pool, std::slice::from_ref(message), true).await?;
+    Ok(inserted > 0)
+}
+
+/// user_id の after 以降のメッセージを API からすべて取得する (DB には保存しない)
+///
+/// 検索結果が上限の 10000 件に達していて、すべてを取得できない場合は None を返す
+pub async fn fetch_all_messages_after<Tz>(
+    api: &ApiClient,
+    user_id: &str,
+    after: &DateTime<Tz>,
+) -> anyhow::Result<Option<Vec<MessageRecord>>>
+where
+    Tz: TimeZone,
+    Tz::Offset: std::fmt::Display,
+{
+    let mut messages = Vec::new();
+    loop {
+        let (total_hits, res_messages) = api
+            .get_messages_with_time_section(
+                user_id,
+                messages.len(),
+                None::<&DateTime<Local>>,
+                Some(after),
+            )
+            .await?;
+        if total_hits >= SEARCH_HITS_LIMIT {
+            return Ok(None);
+        }
+        if res_messages.is_empty() {
+            break;
+        }
+        messages.extend(res_messages.iter().map(MessageRecord::from));
+        if messages.len() >= total_hits {
+            break;
+        }
+    }
+    Ok(Some(messages))
+}
+
+/// user_id のまだ取得していないメッセージを API から取得し、DB に保存する
 ///
-/// (ただし、traQ の検索の仕様上、件数は 10000 件を超えると 10000 件と表示されるため、10000 件までしか取得しない)
+/// 取得する期間を、検索結果がそれぞれ上限の 10000 件に収まるように分割して DB に記録し、期間ごとに取得する
+/// 取得の途中で終了した場合は、次回は記録された進捗から再開する
 ///
 /// # Arguments
 /// * `pool` - DB のコネクションプール
-/// * `before` - 取得するメッセージの期間の終わり
-/// * `after` - 取得するメッセージの期間の始まり
-/// * `interval_ms` - メッセージを取得する間隔 (ミリ秒)
-async fn fetch_messages_as_match_as_possible_at_once<TzB, TzA>(
+/// * `api` - traQ API のクライアント
+/// * `user_id` - 取得するメッセージの投稿者
+/// * `force` - true のときは、これまでの進捗を破棄して最初から取得し直す
+pub async fn backfill_messages(
+    pool: &MySqlPool,
+    api: &ApiClient,
+    user_id: &str,
+    force: bool,
+) -> anyhow::Result<()> {
+    if force {
+        db::delete_fetch_windows(pool, user_id).await?;
+    }
+
+    // 前回取得しきれなかった期間から再開する
+    for window in db::get_pending_fetch_windows(pool, user_id).await? {
+        fetch_window(pool, api, window).await?;
+    }
+
+    // 記録が無い場合は、この機能より前に保存されたメッセージの続きから取得する
+    let since = match db::get_latest_fetch_window_until(pool, user_id).await? {
+        Some(until) => Some(until),
+        None if force => None,
+        None => get_latest_message(pool, user_id)
+            .await?
+            .map(|m| m.created_at),
+    };
+    let until = Utc::now().naive_utc().with_nanosecond(0).unwrap();
+    let windows = plan_fetch_windows(api, user_id, since, until).await?;
+    db::insert_fetch_windows(pool, &windows).await?;
+
+    for window in windows.into_iter().filter(|w| !w.done) {
+        fetch_window(pool, api, window).await?;
+    }
+    Ok(())
+}
+
+/// user_id の期間 (since, until] のメッセージを取得する期間を、API の検索結果の件数をもとに決める
+async fn plan_fetch_windows(
+    api: &ApiClient,
+    user_id: &str,
+    since: Option<NaiveDateTime>,
+    until: NaiveDateTime,
+) -> anyhow::Result<Vec<FetchWindowRecord>> {
+    let windows = split_into_windows(since, until, |since, until| async move {
+        let (total_hits, _) = api
+            .get_messages_with_time_section(
+                user_id,
+                0,
+                Some(&search_before(until)),
+                since.map(|since| Utc.from_utc_datetime(&since)).as_ref(),
+            )
+            .await?;
+        Ok(total_hits)
+    })
+    .await?
+    .into_iter()
+    .map(|(since, until, total_hits)| FetchWindowRecord {
+        user_id: user_id.to_string(),
+        since,
+        until,
+        total_hits: total_hits as i64,
+        fetched: 0,
+        done: total_hits == 0,
+    })
+    .collect();
+    Ok(windows)
+}
+
+/// 期間 (since, until] を検索するときの before
+///
+/// traQ の検索の before はその時刻ちょうどのメッセージを含まないので、
+/// 期間の境界に投稿されたメッセージも含まれるように、メッセージの時刻の精度の分だけ後ろにずらす
+fn search_before(until: NaiveDateTime) -> DateTime<Utc> {
+    Utc.from_utc_datetime(&until) + chrono::Duration::microseconds(1)
+}
+
+/// 期間を分割するときの、最小の期間の長さ
+const MIN_WINDOW_SECONDS: i64 = 1;
+
+/// 期間 (since, until] のメッセージを、取得済みの件数の続きから取得して DB に保存する
+async fn fetch_window(
     pool: &MySqlPool,
-    before: Option<&DateTime<TzB>>,
-    after: Option<&DateTime<TzA>>,
-    interval_ms: u64,
-) -> anyhow::Result<Vec<MessageRecord>>
+    api: &ApiClient,
+    window: FetchWindowRecord,
+) -> anyhow::Result<()> {
+    fetch_window_with(api, window, |messages, window| async move {
+        db::insert_messages(pool, &messages, false).await?;
+        db::update_fetch_window_progress(pool, &window).await?;
+        Ok(())
+    })
+    .await
+}
+
+/// 期間 (since, until] のメッセージを、取得済みの件数の続きから取得する
+///
+/// 1 回取得するごとに、取得したメッセージと進捗を反映した期間を save に渡す
+async fn fetch_window_with<F, Fut>(
+    api: &ApiClient,
+    mut window: FetchWindowRecord,
+    mut save: F,
+) -> anyhow::Result<()>
 where
-    TzB: TimeZone,
-    TzB::Offset: std::fmt::Display,
-    TzA: TimeZone,
-    TzA::Offset: std::fmt::Display,
+    F: FnMut(Vec<MessageRecord>, FetchWindowRecord) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
 {
-    let mut messages = Vec::new();
-    let (limit, res_messages) = api::get_messages_with_time_section(0, before, after).await?;
+    let since = Utc.from_utc_datetime(&window.since);
+    let until = search_before(window.until);
+    while window.fetched < window.total_hits {
+        let (_, res_messages) = api
+            .get_messages_with_time_section(
+                &window.user_id,
+                window.fetched as usize,
+                Some(&until),
+                Some(&since),
+            )
+            .await?;
+        if res_messages.is_empty() {
+            break;
+        }
+        window.fetched += res_messages.len() as i64;
+        save(
+            res_messages.iter().map(MessageRecord::from).collect(),
+            window.clone(),
+        )
+        .await?;
+    }
+    window.done = true;
+    save(Vec::new(), window.clone()).await?;
+    debug!(
+        "fetched {} messages of {} in ({}, {}]",
+        window.fetched, window.user_id, window.since, window.until
+    );
+    Ok(())
+}
 
-    db::insert_messages(
-        pool,
-        &res_messages
-            .iter()
-            .map(MessageRecord::from)
-            .collect::<Vec<MessageRecord>>(),
-    )
-    .await?;
+/// 期間 (since, until] を、count_hits で数えた件数が検索の上限未満になるように分割し、
+/// (since, until, 件数) を古い順に返す
+///
+/// since が None のときは、メッセージが存在しない時点まで遡って始まりを決める
+async fn split_into_windows<F, Fut>(
+    since: Option<NaiveDateTime>,
+    until: NaiveDateTime,
+    mut count_hits: F,
+) -> anyhow::Result<Vec<(NaiveDateTime, NaiveDateTime, usize)>>
+where
+    F: FnMut(Option<NaiveDateTime>, NaiveDateTime) -> Fut,
+    Fut: Future<Output = anyhow::Result<usize>>,
+{
+    let since = match since {
+        Some(since) => since,
+        None => {
+            let mut span = chrono::Duration::days(365);
+            loop {
+                let since = until - span;
+                if count_hits(None, since).await? == 0 {
+                    break since;
+                }
+                span = span * 2;
+            }
+        }
+    };
 
-    messages.extend(res_messages);
+    let mut windows = Vec::new();
+    let mut stack = vec![(since, until)];
+    while let Some((since, until)) = stack.pop() {
+        let hits = count_hits(Some(since), until).await?;
+        let seconds = (until - since).num_seconds();
+        if hits < SEARCH_HITS_LIMIT || seconds <= MIN_WINDOW_SECONDS {
+            windows.push((since, until, hits));
+            continue;
+        }
+        let mid = since + chrono::Duration::seconds(seconds / 2);
+        // 古い方から取り出されるように、新しい方を先に積む
+        stack.push((mid, until));
+        stack.push((since, mid));
+    }
+    Ok(windows)
+}
 
-    let mut now = messages.len();
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, sync::Mutex};
 
-    while now < limit {
-        let (_, res_messages) = api::get_messages_with_time_section(now, before, after).await?;
+    use chrono::NaiveDate;
 
-        let interval = tokio::spawn(async move {
-            std::thread::sleep(std::time::Duration::from_micros(interval_ms));
-        });
+    use super::*;
+    use crate::fake_traq::{fake_traq, message};
+
+    fn time(day: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2022, 1, day)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+    }
 
-        db::insert_messages(
-            pool,
-            &res_messages
+    /// times に含まれる時刻のうち、(since, until] に含まれるものを数える
+    fn counter(
+        times: &[NaiveDateTime],
+    ) -> impl FnMut(Option<NaiveDateTime>, NaiveDateTime) -> std::future::Ready<anyhow::Result<usize>> + '_
+    {
+        |since, until| {
+            let count = times
                 .iter()
-                .map(MessageRecord::from)
-                .collect::<Vec<MessageRecord>>(),
-        )
-        .await?;
+                .filter(|t| since.is_none_or(|since| since < **t) && **t <= until)
+                .count();
+            std::future::ready(Ok(count))
+        }
+    }
 
-        messages.extend(res_messages);
+    #[tokio::test]
+    async fn test_split_into_windows_under_limit() {
+        let times = vec![time(2), time(3)];
+        let windows = split_into_windows(Some(time(1)), time(10), counter(&times))
+            .await
+            .unwrap();
+        assert_eq!(windows, vec![(time(1), time(10), 2)]);
+    }
 
-        now = messages.len();
+    #[tokio::test]
+    async fn test_split_into_windows_over_limit() {
+        // 1 日目と 9 日目に上限ちょうどずつ
+        let times = std::iter::repeat_n(time(1) + chrono::Duration::seconds(1), SEARCH_HITS_LIMIT)
+            .chain(std::iter::repeat_n(time(9), SEARCH_HITS_LIMIT))
+            .collect::<Vec<_>>();
+        let windows = split_into_windows(Some(time(1)), time(10), counter(&times))
+            .await
+            .unwrap();
 
-        interval.await.unwrap();
+        assert!(windows.windows(2).all(|w| w[0].1 == w[1].0));
+        assert_eq!(windows.first().unwrap().0, time(1));
+        assert_eq!(windows.last().unwrap().1, time(10));
+        assert_eq!(
+            windows.iter().map(|w| w.2).sum::<usize>(),
+            SEARCH_HITS_LIMIT * 2
+        );
     }
 
-    if messages.len() > limit {
-        messages.truncate(limit);
+    #[tokio::test]
+    async fn test_split_into_windows_from_beginning() {
+        let times = vec![time(1), time(2)];
+        let windows = split_into_windows(None, time(10), counter(&times))
+            .await
+            .unwrap();
+        assert_eq!(windows.len(), 1);
+        assert!(windows[0].0 < time(1));
+        assert_eq!(windows[0].2, 2);
     }
 
-    Ok(messages.iter().map(MessageRecord::from).collect::<Vec<_>>())
-}
+    #[tokio::test]
+    async fn test_fetch_all_messages_after_paging() {
+        let traq = fake_traq();
+        let user_id = "messages-paging-user";
+        let after = Utc.from_utc_datetime(&time(1));
+        traq.add_messages((0..250).map(|i| {
+            message(
+                &format!("messages-paging-{}", i),
+                user_id,
+                "c",
+                after + chrono::Duration::minutes(i + 1),
+            )
+        }));
 
-/// ある時点より新しいメッセージすべてを最大 limit 件取得し、DB に保存する
-/// (ただし、DB に保存される件数は limit 件を上回る可能性がある)
-pub async fn fetch_messages<Tz>(
-    pool: &MySqlPool,
-    limit: Option<usize>,
-    after: Option<DateTime<Tz>>,
-) -> anyhow::Result<Vec<MessageRecord>>
-where
-    Tz: TimeZone,
-    Tz::Offset: std::fmt::Display,
-{
-    let mut messages = fetch_messages_as_match_as_possible_at_once(
-        pool,
-        None::<&DateTime<Local>>,
-        after.as_ref(),
-        300,
-    )
-    .await?;
-    if messages.is_empty() {
-        return Ok(messages);
+        let messages = fetch_all_messages_after(&traq.client(), user_id, &after)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(messages.len(), 250);
+        assert!(messages
+            .windows(2)
+            .all(|w| w[0].created_at < w[1].created_at));
     }
-    loop {
-        if let Some(limit) = limit {
-            if messages.len() >= limit {
-                break;
-            }
-        }
-        let oldest_message = messages.last().unwrap();
-        let oldest_message_id = &oldest_message.id;
-        let oldest_message_created_at = oldest_message.created_at;
-        let oldest_message_created_at_local = naive_to_local(oldest_message_created_at);
-        let mut older_messages = fetch_messages_as_match_as_possible_at_once(
-            pool,
-            Some(&oldest_message_created_at_local),
-            after.as_ref(),
-            300,
-        )
-        .await?;
 
-        // 現状保持しているメッセージの中で最も古いメッセージより新しいメッセージのみに絞る
-        older_messages
-            .retain(|m| m.id != *oldest_message_id && m.created_at >= oldest_message_created_at);
+    #[tokio::test]
+    async fn test_backfill_over_limit() {
+        let traq = fake_traq();
+        let client = traq.client();
+        let user_id = "messages-backfill-user";
+        let since = time(1);
+        let count = SEARCH_HITS_LIMIT + 50;
+        traq.add_messages((0..count as i64).map(|i| {
+            message(
+                &format!("messages-backfill-{}", i),
+                user_id,
+                "c",
+                Utc.from_utc_datetime(&since) + chrono::Duration::seconds(i + 1),
+            )
+        }));
 
-        if older_messages.is_empty() {
-            break;
+        let windows = plan_fetch_windows(&client, user_id, Some(since), time(10))
+            .await
+            .unwrap();
+        assert!(windows.len() >= 2);
+        assert!(windows
+            .iter()
+            .all(|w| (w.total_hits as usize) < SEARCH_HITS_LIMIT));
+        assert!(windows.windows(2).all(|w| w[0].until == w[1].since));
+        assert_eq!(
+            windows.iter().map(|w| w.total_hits).sum::<i64>(),
+            count as i64
+        );
+
+        let fetched = Mutex::new(Vec::new());
+        for window in windows {
+            let total_hits = window.total_hits;
+            let mut last = None;
+            fetch_window_with(&client, window, |messages, window| {
+                fetched.lock().unwrap().extend(messages);
+                last = Some(window);
+                std::future::ready(Ok(()))
+            })
+            .await
+            .unwrap();
+            let last = last.unwrap();
+            assert!(last.done);
+            assert_eq!(last.fetched, total_hits);
         }
-        messages.extend(older_messages);
+        let ids = fetched
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .map(|m| m.id)
+            .collect::<HashSet<_>>();
+        assert_eq!(ids.len(), count);
     }
 
-    if let Some(limit) = limit {
-        messages.truncate(limit);
-    }
+    #[tokio::test]
+    async fn test_fetch_all_messages_after_over_limit() {
+        let traq = fake_traq();
+        let user_id = "messages-limit-user";
+        let after = Utc.from_utc_datetime(&time(1));
+        traq.add_messages((0..SEARCH_HITS_LIMIT as i64).map(|i| {
+            message(
+                &format!("messages-limit-{}", i),
+                user_id,
+                "c",
+                after + chrono::Duration::seconds(i + 1),
+            )
+        }));
 
-    Ok(messages)
+        let messages = fetch_all_messages_after(&traq.client(), user_id, &after)
+            .await
+            .unwrap();
+        assert!(messages.is_none());
+    }
 }
@@ -1,179 +1,610 @@
-use std::env;
+use std::{
+    env, fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use chrono::DateTime;
-use log::debug;
-use serde_json::Value;
+use chrono::{DateTime, Utc};
+use log::{debug, warn};
+use reqwest::{header::RETRY_AFTER, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use tokio::time::Instant;
 use traq_ws_bot::utils::RateLimiter;
 
-use crate::{model::db::MessageRecord, BOT_ACCESS_TOKEN, BOT_ID, TARGET_USER_ID};
+use crate::model::{
+    db::MessageRecord,
+    traq::{BotChannelActionRequest, Message, MessageSearchResult, PostMessageRequest},
+};
 
-const BASE_URL: &str = "https://q.trap.jp/api/v3";
+/// 失敗したリクエストを再試行する最大の回数
+const MAX_RETRIES: u32 = 5;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Message {
-    id: String,
-    channel_id: String,
-    content: String,
-    created_at: String,
+/// 1 回目の再試行までの待ち時間 (以降は 2 倍ずつ増やす)
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// 再試行までの待ち時間の上限
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// 接続を確立するまでの待ち時間の上限
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 1 回のリクエストにかかる時間の上限
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// traQ API の呼び出しに失敗した理由
+#[derive(Debug)]
+pub enum ApiError {
+    /// トークンが不正、または権限が無い (401, 403)
+    Auth(StatusCode),
+    /// rate limit に達した (429)
+    RateLimited { retry_after: Option<Duration> },
+    /// 対象が存在しない (404)
+    NotFound,
+    /// traQ 側のエラー (5xx)
+    Server(StatusCode),
+    /// その他の想定していないステータス
+    UnexpectedStatus(StatusCode),
+    /// レスポンスを解釈できない
+    Decode(String),
+    /// 接続できない、タイムアウトしたなど
+    Request(reqwest::Error),
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Auth(status) => write!(f, "traQ API authorization failed ({})", status),
+            ApiError::RateLimited {
+                retry_after: Some(retry_after),
+            } => write!(
+                f,
+                "traQ API rate limit exceeded (retry after {}s)",
+                retry_after.as_secs()
+            ),
+            ApiError::RateLimited { retry_after: None } => {
+                write!(f, "traQ API rate limit exceeded")
+            }
+            ApiError::NotFound => write!(f, "traQ API resource not found"),
+            ApiError::Server(status) => write!(f, "traQ API server error ({})", status),
+            ApiError::UnexpectedStatus(status) => {
+                write!(f, "unexpected traQ API status ({})", status)
+            }
+            ApiError::Decode(message) => {
+                write!(f, "failed to decode traQ API response: {}", message)
+            }
+            ApiError::Request(e) => write!(f, "traQ API request failed: {}", e),
+        }
+    }
 }
+
+impl std::error::Error for ApiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ApiError::Request(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ApiError {
+    fn from(e: reqwest::Error) -> Self {
+        ApiError::Request(e)
+    }
+}
+
+impl ApiError {
+    /// ステータスとヘッダーからエラーを判定する (成功した場合は None を返す)
+    fn from_response(response: &Response) -> Option<Self> {
+        let status = response.status();
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| parse_retry_after(v, Utc::now()));
+        Self::from_status(status, retry_after)
+    }
+
+    fn from_status(status: StatusCode, retry_after: Option<Duration>) -> Option<Self> {
+        match status {
+            s if s.is_success() => None,
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Some(ApiError::Auth(status)),
+            StatusCode::NOT_FOUND => Some(ApiError::NotFound),
+            StatusCode::TOO_MANY_REQUESTS => Some(ApiError::RateLimited { retry_after }),
+            s if s.is_server_error() => Some(ApiError::Server(status)),
+            _ => Some(ApiError::UnexpectedStatus(status)),
+        }
+    }
+
+    /// 時間をおいて再試行すれば成功する可能性があれば true を返す
+    ///
+    /// 冪等でないリクエスト (メッセージの投稿など) は、traQ が処理していないことが確かな
+    /// 429 と接続の失敗のときだけ再試行する (5xx やタイムアウトでは処理されている可能性がある)
+    pub fn is_retryable(&self, idempotent: bool) -> bool {
+        match self {
+            ApiError::RateLimited { .. } => true,
+            ApiError::Server(_) => idempotent,
+            ApiError::Request(e) => e.is_connect() || (idempotent && e.is_timeout()),
+            _ => false,
+        }
+    }
+}
+
+/// `Retry-After` ヘッダーの値 (秒数または HTTP-date) を、now からの待ち時間にする
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/// 失敗したリクエストの再試行の仕方
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// 再試行する最大の回数
+    pub max_retries: u32,
+    /// 1 回目の再試行までの待ち時間 (以降は 2 倍ずつ増やす)
+    pub initial_backoff: Duration,
+    /// 再試行までの待ち時間の上限
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: MAX_RETRIES,
+            initial_backoff: INITIAL_BACKOFF,
+            max_backoff: MAX_BACKOFF,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// attempt 回目 (0 始まり) の再試行までの待ち時間
+    ///
+    /// `Retry-After` が指定されていればそれに従い、そうでなければ指数的に増やす
+    /// どちらの場合も max_backoff より長くは待たない
+    fn backoff(&self, attempt: u32, error: &ApiError) -> Duration {
+        if let ApiError::RateLimited {
+            retry_after: Some(retry_after),
+        } = error
+        {
+            return (*retry_after).min(self.max_backoff);
+        }
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff)
+    }
+}
+
+/// リクエストの間隔を一定以上に空けるためのもの
+#[derive(Debug)]
+struct Pacer {
+    /// (リクエストの間隔, 次のリクエストを送ってよい時刻)
+    state: Mutex<(Duration, Instant)>,
+}
+
+impl Pacer {
+    fn new(interval: Duration) -> Self {
+        Pacer {
+            state: Mutex::new((interval, Instant::now())),
+        }
+    }
+
+    /// 1 秒あたり requests_per_second 回までにする
+    fn per_second(requests_per_second: f64) -> Self {
+        Self::new(Duration::from_secs_f64(1.0 / requests_per_second))
+    }
+
+    fn set_per_second(&self, requests_per_second: f64) {
+        self.state.lock().unwrap().0 = Duration::from_secs_f64(1.0 / requests_per_second);
+    }
+
+    /// 前のリクエストから間隔が空くまで待つ
+    ///
+    /// 同時に呼ばれた場合は、呼ばれた順に間隔を空けて待ち終わる
+    async fn wait(&self) {
+        let at = {
+            let mut state = self.state.lock().unwrap();
+            let at = state.1.max(Instant::now());
+            state.1 = at + state.0;
+            at
+        };
+        tokio::time::sleep_until(at).await;
+    }
+}
+
 impl From<&Message> for MessageRecord {
     fn from(message: &Message) -> Self {
         MessageRecord {
             id: message.id.clone(),
+            user_id: message.user_id.clone(),
             channel_id: message.channel_id.clone(),
             content: message.content.clone(),
-            created_at: DateTime::parse_from_rfc3339(&message.created_at)
-                .unwrap()
-                .naive_local(),
-        }
-    }
-}
-
-fn create_client() -> reqwest::Client {
-    let mut headers = reqwest::header::HeaderMap::new();
-    let authorization_token = format!("Bearer {}", *BOT_ACCESS_TOKEN);
-    headers.insert(
-        reqwest::header::AUTHORIZATION,
-        reqwest::header::HeaderValue::from_str(&authorization_token).unwrap(),
-    );
-    reqwest::Client::builder()
-        .default_headers(headers)
-        .build()
-        .unwrap()
-}
-
-/// /messages のレスポンスを解釈し、totalHits と messages の中身のタプルを返す
-fn parse_messages_response(res: String) -> anyhow::Result<(usize, Vec<Message>)> {
-    let res_json: Value = serde_json::from_str(&res)?;
-
-    // schema: { "hits": { "content": "string" }[], "totalHits": number } }
-    let hits = res_json["hits"].as_array().unwrap();
-    let total_hits = res_json["totalHits"].as_u64().unwrap() as usize;
-    let messages = hits
-        .iter()
-        .map(|hit| Message {
-            id: hit["id"].as_str().unwrap().to_string(),
-            channel_id: hit["channelId"].as_str().unwrap().to_string(),
-            content: hit["content"].as_str().unwrap().to_string(),
-            created_at: hit["createdAt"].as_str().unwrap().to_string(),
+            created_at: message.created_at.naive_utc(),
+        }
+    }
+}
+
+/// レスポンスの本文を T として解釈する
+async fn decode<T: DeserializeOwned>(response: Response) -> Result<T, ApiError> {
+    let body = response.text().await?;
+    serde_json::from_str(&body).map_err(|e| ApiError::Decode(e.to_string()))
+}
+
+/// traQ API のクライアント
+///
+/// 接続を使い回すため、起動時に 1 つだけ作り、clone して共有する
+#[derive(Debug, Clone)]
+pub struct ApiClient {
+    client: reqwest::Client,
+    base_url: String,
+    bot_id: String,
+    retry: RetryPolicy,
+    /// メッセージの検索の間隔 (clone したもの同士で共有する)
+    search_pacer: Arc<Pacer>,
+}
+
+impl ApiClient {
+    /// base_url の traQ に、bot_id の BOT として access_token で接続するクライアントを作る
+    pub fn new(base_url: &str, bot_id: &str, access_token: &str) -> Result<Self, ApiError> {
+        let mut headers = reqwest::header::HeaderMap::new();
+        let authorization_token = format!("Bearer {}", access_token);
+        headers.insert(
+            reqwest::header::AUTHORIZATION,
+            reqwest::header::HeaderValue::from_str(&authorization_token)
+                .map_err(|_| ApiError::Auth(StatusCode::UNAUTHORIZED))?,
+        );
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .user_agent(USER_AGENT)
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        Ok(ApiClient {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            bot_id: bot_id.to_string(),
+            retry: RetryPolicy::default(),
+            search_pacer: Arc::new(Pacer::new(Duration::ZERO)),
         })
-        .collect::<Vec<Message>>();
-    Ok((total_hits, messages))
-}
-
-/// messages を after と offset に従って叩いて、totalHits と messages の中身のタプルを返す
-pub async fn get_messages_with_time_section<Tz, Tz2>(
-    offset: usize,
-    before: Option<&DateTime<Tz>>,
-    after: Option<&DateTime<Tz2>>,
-) -> anyhow::Result<(usize, Vec<Message>)>
-where
-    Tz: chrono::TimeZone,
-    Tz::Offset: std::fmt::Display,
-    Tz2: chrono::TimeZone,
-    Tz2::Offset: std::fmt::Display,
-{
-    let client = create_client();
-
-    let url = format!("{}/messages", BASE_URL);
-    let query = [
-        ("word", ""),
-        ("from", TARGET_USER_ID),
-        ("limit", "100"),
-        ("offset", &offset.to_string()),
-        ("sort", "createdAt"),
-    ];
-    let mut builder = client.get(&url).query(&query);
-    if let Some(before) = before {
-        let before_str = before.to_rfc3339();
-        builder = builder.query(&[("before", &before_str)]);
-    }
-    if let Some(after) = after {
-        let after_str = after.to_rfc3339();
-        builder = builder.query(&[("after", &after_str)]);
-    }
-    let res = builder.send().await?.text().await?;
-
-    parse_messages_response(res)
-}
-
-/// 指定のチャンネルにメッセージを送信する
-pub async fn post_message(
-    channel_id: String,
-    message: String,
-    rate_limiter: Option<&RateLimiter>,
-) -> anyhow::Result<()> {
-    if env::var("POST_LOCAL").map(|e| e == "1").unwrap_or(false) {
-        debug!("post_message: {}", message);
-        return Ok(());
-    }
-    if let Some(rate_limiter) = rate_limiter {
-        if !rate_limiter.try_acquire() {
-            log::info!("rate limit exceeded with {} on {}", message, channel_id);
+    }
+
+    /// 再試行の仕方を変える
+    pub fn with_retry_policy(self, retry: RetryPolicy) -> Self {
+        ApiClient { retry, ..self }
+    }
+
+    /// メッセージの検索を 1 秒あたり requests_per_second 回までにする
+    pub fn with_search_rate(self, requests_per_second: f64) -> Self {
+        ApiClient {
+            search_pacer: Arc::new(Pacer::per_second(requests_per_second)),
+            ..self
+        }
+    }
+
+    /// メッセージの検索の上限を変える (clone したクライアントにも反映される)
+    pub fn set_search_rate(&self, requests_per_second: f64) {
+        self.search_pacer.set_per_second(requests_per_second);
+    }
+
+    /// build で作ったリクエストを送信し、再試行できるエラーであれば待ってから再試行する
+    ///
+    /// idempotent が false のリクエストは、二重に処理されうる場合には再試行しない
+    async fn send_with_retry(
+        &self,
+        idempotent: bool,
+        build: impl Fn(&reqwest::Client) -> RequestBuilder,
+    ) -> Result<Response, ApiError> {
+        let mut attempt = 0;
+        loop {
+            let error = match build(&self.client).send().await {
+                Ok(response) => match ApiError::from_response(&response) {
+                    None => return Ok(response),
+                    Some(error) => error,
+                },
+                Err(e) => ApiError::Request(e),
+            };
+            if !error.is_retryable(idempotent) || attempt >= self.retry.max_retries {
+                return Err(error);
+            }
+            let wait = self.retry.backoff(attempt, &error);
+            warn!("{}, retrying in {:?}", error, wait);
+            tokio::time::sleep(wait).await;
+            attempt += 1;
+        }
+    }
+
+    /// user_id の messages を after と offset に従って叩いて、totalHits と messages の中身のタプルを返す
+    pub async fn get_messages_with_time_section<Tz, Tz2>(
+        &self,
+        user_id: &str,
+        offset: usize,
+        before: Option<&DateTime<Tz>>,
+        after: Option<&DateTime<Tz2>>,
+    ) -> Result<(usize, Vec<Message>), ApiError>
+    where
+        Tz: chrono::TimeZone,
+        Tz::Offset: std::fmt::Display,
+        Tz2: chrono::TimeZone,
+        Tz2::Offset: std::fmt::Display,
+    {
+        let url = format!("{}/messages", self.base_url);
+        let offset = offset.to_string();
+        let mut query = vec![
+            ("word", ""),
+            ("from", user_id),
+            ("limit", "100"),
+            ("offset", &offset),
+            ("sort", "createdAt"),
+        ];
+        let before_str = before.map(|before| before.to_rfc3339());
+        if let Some(before) = &before_str {
+            query.push(("before", before));
+        }
+        let after_str = after.map(|after| after.to_rfc3339());
+        if let Some(after) = &after_str {
+            query.push(("after", after));
+        }
+        self.search_pacer.wait().await;
+        let response = self
+            .send_with_retry(true, |client| client.get(&url).query(&query))
+            .await?;
+        let result: MessageSearchResult = decode(response).await?;
+
+        Ok((result.total_hits, result.hits))
+    }
+
+    /// 指定のチャンネルにメッセージを送信する
+    pub async fn post_message(
+        &self,
+        channel_id: String,
+        message: String,
+        rate_limiter: Option<&RateLimiter>,
+    ) -> Result<(), ApiError> {
+        if env::var("POST_LOCAL").map(|e| e == "1").unwrap_or(false) {
+            debug!("post_message: {}", message);
             return Ok(());
         }
+        if let Some(rate_limiter) = rate_limiter {
+            if !rate_limiter.try_acquire() {
+                log::info!("rate limit exceeded with {} on {}", message, channel_id);
+                return Ok(());
+            }
+        }
+
+        let url = format!("{}/channels/{}/messages", self.base_url, channel_id);
+
+        let request_body = PostMessageRequest {
+            content: message,
+            embed: false,
+        };
+
+        let response = self
+            .send_with_retry(false, |client| client.post(&url).json(&request_body))
+            .await?;
+        let posted: Message = decode(response).await?;
+
+        debug!("posted message {} on {}", posted.id, posted.channel_id);
+        Ok(())
     }
-    let client = create_client();
 
-    let url = format!("{}/channels/{}/messages", BASE_URL, channel_id);
+    /// 指定のチャンネルに参加する
+    pub async fn join_channel(&self, channel_id: String) -> Result<(), ApiError> {
+        let url = format!("{}/bots/{}/actions/join", self.base_url, self.bot_id);
 
-    let request_body = serde_json::json!({
-        "content": message,
-        "embed": false,
-    });
+        let request_body = BotChannelActionRequest { channel_id };
+        self.send_with_retry(true, |client| client.post(&url).json(&request_body))
+            .await?;
+
+        debug!("joined {}", request_body.channel_id);
+        Ok(())
+    }
 
-    let res = client
-        .post(&url)
-        .header(reqwest::header::CONTENT_TYPE, "application/json")
-        .body(request_body.to_string())
-        .send()
-        .await?
-        .text()
-        .await?;
+    /// 指定のチャンネルから退出する
+    pub async fn leave_channel(&self, channel_id: String) -> Result<(), ApiError> {
+        let url = format!("{}/bots/{}/actions/leave", self.base_url, self.bot_id);
 
-    debug!("{}", res);
-    Ok(())
+        let request_body = BotChannelActionRequest { channel_id };
+        self.send_with_retry(true, |client| client.post(&url).json(&request_body))
+            .await?;
+
+        debug!("left {}", request_body.channel_id);
+        Ok(())
+    }
 }
 
-/// 指定のチャンネルに参加する
-pub async fn join_channel(channel_id: String) -> anyhow::Result<()> {
-    let client = create_client();
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
 
-    let url = format!("{}/bots/{}/actions/join", BASE_URL, BOT_ID);
+    use super::*;
+    use crate::fake_traq::{fake_traq, message};
 
-    let res = client
-        .post(&url)
-        .header(reqwest::header::CONTENT_TYPE, "application/json")
-        .body(
-            serde_json::json!({
-                "channelId": channel_id,
-            })
-            .to_string(),
-        )
-        .send()
-        .await?
-        .text()
-        .await?;
+    #[test]
+    fn test_from_status() {
+        assert!(ApiError::from_status(StatusCode::OK, None).is_none());
+        assert!(matches!(
+            ApiError::from_status(StatusCode::UNAUTHORIZED, None),
+            Some(ApiError::Auth(_))
+        ));
+        assert!(matches!(
+            ApiError::from_status(StatusCode::NOT_FOUND, None),
+            Some(ApiError::NotFound)
+        ));
+        assert!(matches!(
+            ApiError::from_status(StatusCode::BAD_GATEWAY, None),
+            Some(ApiError::Server(_))
+        ));
+        assert!(matches!(
+            ApiError::from_status(StatusCode::BAD_REQUEST, None),
+            Some(ApiError::UnexpectedStatus(_))
+        ));
+        let rate_limited =
+            ApiError::from_status(StatusCode::TOO_MANY_REQUESTS, Some(Duration::from_secs(3)))
+                .unwrap();
+        assert!(rate_limited.is_retryable(true));
+        assert!(rate_limited.is_retryable(false));
+        assert!(!ApiError::NotFound.is_retryable(true));
+        let server_error = ApiError::Server(StatusCode::BAD_GATEWAY);
+        assert!(server_error.is_retryable(true));
+        assert!(!server_error.is_retryable(false));
+    }
 
-    debug!("{:?}", res);
-    Ok(())
-}
+    #[test]
+    fn test_parse_retry_after() {
+        let now = Utc.with_ymd_and_hms(2015, 10, 21, 7, 28, 0).unwrap();
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let retry = RetryPolicy::default();
+        let error = ApiError::Server(StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(retry.backoff(0, &error), Duration::from_secs(1));
+        assert_eq!(retry.backoff(3, &error), Duration::from_secs(8));
+        assert_eq!(retry.backoff(10, &error), MAX_BACKOFF);
+        let error = ApiError::RateLimited {
+            retry_after: Some(Duration::from_secs(42)),
+        };
+        assert_eq!(retry.backoff(0, &error), Duration::from_secs(42));
+        let error = ApiError::RateLimited {
+            retry_after: Some(Duration::from_secs(3600)),
+        };
+        assert_eq!(retry.backoff(0, &error), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_message_record() {
+        let result: MessageSearchResult = serde_json::from_str(include_str!(
+            "../../tests/fixtures/traq/search_messages.json"
+        ))
+        .unwrap();
+        let record = MessageRecord::from(&result.hits[1]);
+        assert_eq!(record.id, result.hits[1].id);
+        assert_eq!(record.created_at.to_string(), "2022-07-31 14:59:59.123456");
+    }
 
-/// 指定のチャンネルから退出する
-pub async fn leave_channel(channel_id: String) -> anyhow::Result<()> {
-    let client = create_client();
+    #[tokio::test]
+    async fn test_get_messages_with_time_section() {
+        let traq = fake_traq();
+        let client = traq.client();
+        let user_id = "api-search-user";
+        let time = |hour| Utc.with_ymd_and_hms(2022, 8, 1, hour, 0, 0).unwrap();
+        traq.add_messages(
+            (0..5).map(|i| message(&format!("api-search-{}", i), user_id, "c", time(i))),
+        );
 
-    let url = format!("{}/bots/{}/actions/leave", BASE_URL, BOT_ID);
+        let (total_hits, messages) = client
+            .get_messages_with_time_section(user_id, 1, Some(&time(4)), Some(&time(0)))
+            .await
+            .unwrap();
+        assert_eq!(total_hits, 3);
+        assert_eq!(
+            messages.iter().map(|m| m.id.as_str()).collect::<Vec<_>>(),
+            vec!["api-search-2", "api-search-3"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_rate() {
+        let traq = fake_traq();
+        let client = traq.client().with_search_rate(20.0);
+        let after = Utc.with_ymd_and_hms(2022, 8, 1, 0, 0, 0).unwrap();
 
-    let res = client
-        .post(&url)
-        .header(reqwest::header::CONTENT_TYPE, "application/json")
-        .body(format!(r#"{{"channelId": "{}"}}"#, channel_id))
-        .send()
-        .await?
-        .text()
-        .await?;
+        let started_at = std::time::Instant::now();
+        for _ in 0..5 {
+            client
+                .get_messages_with_time_section(
+                    "api-rate-user",
+                    0,
+                    None::<&DateTime<Utc>>,
+                    Some(&after),
+                )
+                .await
+                .unwrap();
+        }
+        // 1 回目は待たないので、4 回分の間隔が空く
+        assert!(started_at.elapsed() >= Duration::from_millis(200));
+    }
 
-    debug!("{}", res);
-    Ok(())
+    #[tokio::test]
+    async fn test_post_message() {
+        let traq = fake_traq();
+        let client = traq.client();
+        client
+            .post_message("api-post".to_string(), "こんにちは".to_string(), None)
+            .await
+            .unwrap();
+        assert_eq!(traq.posted("api-post"), vec!["こんにちは"]);
+    }
+
+    #[tokio::test]
+    async fn test_post_message_with_rate_limiter() {
+        let traq = fake_traq();
+        let client = traq.client();
+        let rate_limiter = RateLimiter::new(2, Duration::from_secs(60));
+        for i in 0..3 {
+            client
+                .post_message(
+                    "api-limiter".to_string(),
+                    i.to_string(),
+                    Some(&rate_limiter),
+                )
+                .await
+                .unwrap();
+        }
+        assert_eq!(traq.posted("api-limiter"), vec!["0", "1"]);
+    }
+
+    #[tokio::test]
+    async fn test_post_message_retry_after() {
+        let traq = fake_traq();
+        let client = traq.client();
+        traq.rate_limit("api-retry", 2);
+        client
+            .post_message("api-retry".to_string(), "retried".to_string(), None)
+            .await
+            .unwrap();
+        assert_eq!(traq.posted("api-retry"), vec!["retried"]);
+
+        let client = client.with_retry_policy(RetryPolicy {
+            max_retries: 1,
+            ..RetryPolicy::default()
+        });
+        traq.rate_limit("api-retry-exhausted", 2);
+        let res = client
+            .post_message("api-retry-exhausted".to_string(), "x".to_string(), None)
+            .await;
+        assert!(matches!(res, Err(ApiError::RateLimited { .. })));
+        assert!(traq.posted("api-retry-exhausted").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_join_and_leave_channel() {
+        let traq = fake_traq();
+        let client = traq.client();
+        client.join_channel("api-join".to_string()).await.unwrap();
+        assert!(traq.is_joined("api-join"));
+        client.leave_channel("api-join".to_string()).await.unwrap();
+        assert!(!traq.is_joined("api-join"));
+    }
 }
@@ -1,12 +1,13 @@
 use std::env;
 
-use chrono::NaiveDateTime;
+use chrono::{NaiveDateTime, NaiveTime};
 use dotenv::dotenv;
 use sqlx::mysql::MySqlPoolOptions;
-use sqlx::{FromRow, MySqlPool};
+use sqlx::{FromRow, MySql, MySqlPool, Transaction};
 
 #[derive(Debug, FromRow)]
 pub struct MarkovCacheRecord {
+    pub user_id: String,
     pub cache: String,
     pub last_update: NaiveDateTime,
 }
@@ -14,14 +15,70 @@ pub struct MarkovCacheRecord {
 #[derive(Debug, FromRow)]
 pub struct MessageRecord {
     pub id: String,
+    pub user_id: String,
     pub channel_id: String,
     pub content: String,
     pub created_at: NaiveDateTime,
 }
 
+/// DB に保存されているメッセージと、その状態
 #[derive(Debug, FromRow)]
-pub struct FrequencyRecord {
+pub struct StoredMessageRecord {
+    #[sqlx(flatten)]
+    pub message: MessageRecord,
+    /// markov chain に反映済みか
+    pub learned: bool,
+}
+
+/// 編集・削除されたメッセージ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageChange {
+    pub id: String,
+    /// 編集後の内容 (削除された場合は None)
+    pub content: Option<String>,
+}
+
+/// メッセージを取得する期間 (since, until] と、その取得の進捗
+#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
+pub struct FetchWindowRecord {
+    pub user_id: String,
+    pub since: NaiveDateTime,
+    pub until: NaiveDateTime,
+    /// 期間内のメッセージの件数
+    pub total_hits: i64,
+    /// 取得済みの件数
+    pub fetched: i64,
+    pub done: bool,
+}
+
+/// チャンネルごとの定期投稿の予定
+#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
+pub struct ScheduleRecord {
+    pub id: i64,
     pub channel_id: String,
+    /// 投稿する時刻の cron 式 (設定のタイムゾーン)
+    pub cron: String,
+    /// 投稿を最大でこの分数だけランダムに遅らせる
+    pub jitter_minutes: i32,
+}
+
+/// 投稿を控える時間帯 (設定のタイムゾーンの時刻)
+#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
+pub struct QuietHoursRecord {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+/// ユーザーに付与した権限 (channel_id が空ならすべてのチャンネル)
+#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
+pub struct RoleRecord {
+    pub user_id: String,
+    pub channel_id: String,
+    pub role: String,
+}
+
+#[derive(Debug, FromRow)]
+pub struct FrequencyRecord {
     pub frequency: i64,
 }
 
@@ -43,55 +100,299 @@ pub async fn connect_db() -> anyhow::Result<MySqlPool> {
     Ok(pool)
 }
 
-/// メッセージを保存する
-pub async fn insert_messages(pool: &MySqlPool, messages: &[MessageRecord]) -> anyhow::Result<()> {
+/// メッセージを保存し、新しく保存された件数を返す
+///
+/// streamed が true のときは websocket で受け取ったメッセージとして保存する
+/// (API から取得したメッセージは、既に保存されていれば streamed を false にする)
+pub async fn insert_messages(
+    pool: &MySqlPool,
+    messages: &[MessageRecord],
+    streamed: bool,
+) -> anyhow::Result<u64> {
     if messages.is_empty() {
-        return Ok(());
+        return Ok(0);
     }
 
-    let query = format!(
-        "INSERT IGNORE INTO messages (id, channel_id, content, created_at) VALUES {};",
-        messages
-            .iter()
-            .map(|_| "(?, ?, ?, ?)")
-            .collect::<Vec<_>>()
-            .join(",")
-    );
+    let values = messages
+        .iter()
+        .map(|_| "(?, ?, ?, ?, ?, ?)")
+        .collect::<Vec<_>>()
+        .join(",");
+    let query = if streamed {
+        format!(
+            "INSERT IGNORE INTO messages (id, user_id, channel_id, content, created_at, streamed) VALUES {};",
+            values
+        )
+    } else {
+        format!(
+            "INSERT INTO messages (id, user_id, channel_id, content, created_at, streamed) VALUES {} ON DUPLICATE KEY UPDATE streamed = FALSE;",
+            values
+        )
+    };
 
     let mut query = sqlx::query(&query);
     for message in messages {
         query = query.bind(&message.id);
+        query = query.bind(&message.user_id);
         query = query.bind(&message.channel_id);
         query = query.bind(&message.content);
         query = query.bind(message.created_at);
+        query = query.bind(streamed);
     }
-    query.execute(pool).await?;
+    let inserted = query.execute(pool).await?.rows_affected();
 
-    Ok(())
+    Ok(inserted)
 }
 
-/// メッセージを取得する
-pub async fn get_messages(pool: &MySqlPool) -> anyhow::Result<Vec<MessageRecord>> {
-    let messages: Vec<MessageRecord> = sqlx::query_as("SELECT * FROM messages;")
-        .fetch_all(pool)
+/// user_id のメッセージを取得する
+pub async fn get_messages(pool: &MySqlPool, user_id: &str) -> anyhow::Result<Vec<MessageRecord>> {
+    let messages: Vec<MessageRecord> =
+        sqlx::query_as("SELECT * FROM messages WHERE user_id = ? AND deleted = FALSE;")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?;
+    Ok(messages)
+}
+
+/// user_id のメッセージのうち、まだ markov chain に反映されていないものを取得する
+pub async fn get_unlearned_messages(
+    pool: &MySqlPool,
+    user_id: &str,
+) -> anyhow::Result<Vec<MessageRecord>> {
+    let messages: Vec<MessageRecord> = sqlx::query_as(
+        "SELECT * FROM messages WHERE user_id = ? AND learned = FALSE AND deleted = FALSE;",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(messages)
+}
+
+/// user_id のすべてのメッセージを markov chain に未反映の状態に戻す
+pub async fn reset_learned_messages(pool: &MySqlPool, user_id: &str) -> anyhow::Result<()> {
+    sqlx::query("UPDATE messages SET learned = FALSE WHERE user_id = ?;")
+        .bind(user_id)
+        .execute(pool)
         .await?;
+    Ok(())
+}
+
+/// API から取得した user_id のメッセージのうち、最も新しいものを取得する
+///
+/// websocket で受け取ったメッセージは、BOT が参加していないチャンネルのメッセージを取りこぼさないように除く
+pub async fn get_latest_message(
+    pool: &MySqlPool,
+    user_id: &str,
+) -> anyhow::Result<Option<MessageRecord>> {
+    let message: Option<MessageRecord> = sqlx::query_as(
+        "SELECT * FROM messages WHERE user_id = ? AND streamed = FALSE ORDER BY created_at DESC LIMIT 1;",
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(message)
+}
+
+/// ids のうち、削除されていないメッセージを取得する
+pub async fn get_stored_messages(
+    pool: &MySqlPool,
+    ids: &[String],
+) -> anyhow::Result<Vec<StoredMessageRecord>> {
+    let mut messages = Vec::new();
+    for ids in ids.chunks(1000) {
+        let query = format!(
+            "SELECT * FROM messages WHERE id IN ({}) AND deleted = FALSE;",
+            ids.iter().map(|_| "?").collect::<Vec<_>>().join(",")
+        );
+        let mut query = sqlx::query_as(&query);
+        for id in ids {
+            query = query.bind(id);
+        }
+        messages.extend(query.fetch_all(pool).await?);
+    }
     Ok(messages)
 }
 
-pub async fn get_latest_message(pool: &MySqlPool) -> anyhow::Result<Option<MessageRecord>> {
-    let message: Option<MessageRecord> =
-        sqlx::query_as("SELECT * FROM messages ORDER BY created_at DESC LIMIT 1;")
+/// API から取得した user_id のメッセージのうち、after 以降に投稿された、削除されていないものを取得する
+pub async fn get_crawled_messages_after(
+    pool: &MySqlPool,
+    user_id: &str,
+    after: NaiveDateTime,
+) -> anyhow::Result<Vec<MessageRecord>> {
+    let messages: Vec<MessageRecord> = sqlx::query_as(
+        "SELECT * FROM messages WHERE user_id = ? AND created_at >= ? AND streamed = FALSE AND deleted = FALSE;",
+    )
+    .bind(user_id)
+    .bind(after)
+    .fetch_all(pool)
+    .await?;
+    Ok(messages)
+}
+
+/// 保存されている user_id の markov chain のキャッシュを取得する
+pub async fn get_markov_cache(
+    pool: &MySqlPool,
+    user_id: &str,
+) -> anyhow::Result<Option<MarkovCacheRecord>> {
+    let cache: Option<MarkovCacheRecord> =
+        sqlx::query_as("SELECT * FROM `markov_cache` WHERE `user_id` = ?;")
+            .bind(user_id)
             .fetch_optional(pool)
             .await?;
-    Ok(message)
+    Ok(cache)
 }
 
-#[allow(dead_code)]
-pub async fn get_frequencies(pool: &MySqlPool) -> anyhow::Result<Vec<FrequencyRecord>> {
-    let frequencies: Vec<FrequencyRecord> = sqlx::query_as("SELECT * FROM `frequency`;")
-        .fetch_all(pool)
+/// markov chain のキャッシュを保存し、learned_ids のメッセージを反映済みにする
+///
+/// キャッシュと反映済みフラグは同一のトランザクションで更新されるため、
+/// 再起動を挟んでも同じメッセージが二重に反映されることはない
+pub async fn update_markov_cache(
+    pool: &MySqlPool,
+    cache: &MarkovCacheRecord,
+    learned_ids: &[String],
+) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    save_markov_cache(&mut tx, cache, learned_ids).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// メッセージの編集・削除を保存する
+///
+/// 変更を反映した markov chain のキャッシュ caches も、同一のトランザクションで保存する
+pub async fn update_messages(
+    pool: &MySqlPool,
+    changes: &[MessageChange],
+    caches: &[(MarkovCacheRecord, Vec<String>)],
+) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    for change in changes {
+        match &change.content {
+            Some(content) => {
+                sqlx::query("UPDATE messages SET content = ? WHERE id = ?;")
+                    .bind(content)
+                    .bind(&change.id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            None => {
+                sqlx::query("UPDATE messages SET deleted = TRUE WHERE id = ?;")
+                    .bind(&change.id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+    }
+    for (cache, learned_ids) in caches {
+        save_markov_cache(&mut tx, cache, learned_ids).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn save_markov_cache(
+    tx: &mut Transaction<'_, MySql>,
+    cache: &MarkovCacheRecord,
+    learned_ids: &[String],
+) -> anyhow::Result<()> {
+    sqlx::query("INSERT INTO `markov_cache` (`user_id`, `cache`, `last_update`) VALUES (?, ?, ?) ON DUPLICATE KEY UPDATE `cache` = ?, `last_update` = ?;")
+        .bind(&cache.user_id)
+        .bind(&cache.cache)
+        .bind(cache.last_update)
+        .bind(&cache.cache)
+        .bind(cache.last_update)
+        .execute(&mut **tx)
         .await?;
-    Ok(frequencies)
+    for ids in learned_ids.chunks(1000) {
+        let query = format!(
+            "UPDATE messages SET learned = TRUE WHERE id IN ({});",
+            ids.iter().map(|_| "?").collect::<Vec<_>>().join(",")
+        );
+        let mut query = sqlx::query(&query);
+        for id in ids {
+            query = query.bind(id);
+        }
+        query.execute(&mut **tx).await?;
+    }
+    Ok(())
+}
+
+/// メッセージを取得する期間を保存する
+pub async fn insert_fetch_windows(
+    pool: &MySqlPool,
+    windows: &[FetchWindowRecord],
+) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    for window in windows {
+        sqlx::query("INSERT INTO `fetch_windows` (`user_id`, `since`, `until`, `total_hits`, `fetched`, `done`) VALUES (?, ?, ?, ?, ?, ?) ON DUPLICATE KEY UPDATE `until` = ?, `total_hits` = ?, `fetched` = ?, `done` = ?;")
+            .bind(&window.user_id)
+            .bind(window.since)
+            .bind(window.until)
+            .bind(window.total_hits)
+            .bind(window.fetched)
+            .bind(window.done)
+            .bind(window.until)
+            .bind(window.total_hits)
+            .bind(window.fetched)
+            .bind(window.done)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// user_id のメッセージを取得する期間のうち、取得が終わっていないものを古い順に取得する
+pub async fn get_pending_fetch_windows(
+    pool: &MySqlPool,
+    user_id: &str,
+) -> anyhow::Result<Vec<FetchWindowRecord>> {
+    let windows: Vec<FetchWindowRecord> = sqlx::query_as(
+        "SELECT * FROM `fetch_windows` WHERE `user_id` = ? AND `done` = FALSE ORDER BY `since`;",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(windows)
+}
+
+/// user_id のメッセージを取得する期間のうち、最も新しいものの終わりを取得する
+pub async fn get_latest_fetch_window_until(
+    pool: &MySqlPool,
+    user_id: &str,
+) -> anyhow::Result<Option<NaiveDateTime>> {
+    let until: (Option<NaiveDateTime>,) =
+        sqlx::query_as("SELECT MAX(`until`) FROM `fetch_windows` WHERE `user_id` = ?;")
+            .bind(user_id)
+            .fetch_one(pool)
+            .await?;
+    Ok(until.0)
+}
+
+/// 期間の取得の進捗を更新する
+pub async fn update_fetch_window_progress(
+    pool: &MySqlPool,
+    window: &FetchWindowRecord,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        "UPDATE `fetch_windows` SET `fetched` = ?, `done` = ? WHERE `user_id` = ? AND `since` = ?;",
+    )
+    .bind(window.fetched)
+    .bind(window.done)
+    .bind(&window.user_id)
+    .bind(window.since)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// user_id のメッセージを取得する期間をすべて削除し、最初から取得し直せるようにする
+pub async fn delete_fetch_windows(pool: &MySqlPool, user_id: &str) -> anyhow::Result<()> {
+    sqlx::query("DELETE FROM `fetch_windows` WHERE `user_id` = ?;")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    Ok(())
 }
 
 pub async fn get_frequency(
@@ -99,7 +400,7 @@ pub async fn get_frequency(
     channel_id: String,
 ) -> anyhow::Result<Option<FrequencyRecord>> {
     let frequency: Option<FrequencyRecord> =
-        sqlx::query_as("SELECT * FROM `frequency` WHERE `channel_id` = ?;")
+        sqlx::query_as("SELECT `frequency` FROM `frequency` WHERE `channel_id` = ?;")
             .bind(&channel_id)
             .fetch_optional(pool)
             .await?;
@@ -119,3 +420,152 @@ pub async fn update_frequency(
         .await?;
     Ok(())
 }
+
+/// すべての定期投稿の予定を取得する
+pub async fn get_schedules(pool: &MySqlPool) -> anyhow::Result<Vec<ScheduleRecord>> {
+    let schedules: Vec<ScheduleRecord> = sqlx::query_as("SELECT * FROM `schedules` ORDER BY `id`;")
+        .fetch_all(pool)
+        .await?;
+    Ok(schedules)
+}
+
+/// channel_id の定期投稿の予定を取得する
+pub async fn get_channel_schedules(
+    pool: &MySqlPool,
+    channel_id: &str,
+) -> anyhow::Result<Vec<ScheduleRecord>> {
+    let schedules: Vec<ScheduleRecord> =
+        sqlx::query_as("SELECT * FROM `schedules` WHERE `channel_id` = ? ORDER BY `id`;")
+            .bind(channel_id)
+            .fetch_all(pool)
+            .await?;
+    Ok(schedules)
+}
+
+/// 定期投稿の予定を追加し、その ID を返す
+pub async fn insert_schedule(
+    pool: &MySqlPool,
+    channel_id: &str,
+    cron: &str,
+    jitter_minutes: i32,
+) -> anyhow::Result<i64> {
+    let result = sqlx::query(
+        "INSERT INTO `schedules` (`channel_id`, `cron`, `jitter_minutes`) VALUES (?, ?, ?);",
+    )
+    .bind(channel_id)
+    .bind(cron)
+    .bind(jitter_minutes)
+    .execute(pool)
+    .await?;
+    Ok(result.last_insert_id() as i64)
+}
+
+/// channel_id の定期投稿の予定を削除し、削除できたかどうかを返す
+pub async fn delete_schedule(pool: &MySqlPool, channel_id: &str, id: i64) -> anyhow::Result<bool> {
+    let result = sqlx::query("DELETE FROM `schedules` WHERE `id` = ? AND `channel_id` = ?;")
+        .bind(id)
+        .bind(channel_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// channel_id の投稿を控える時間帯を取得する
+pub async fn get_quiet_hours(
+    pool: &MySqlPool,
+    channel_id: &str,
+) -> anyhow::Result<Option<QuietHoursRecord>> {
+    let quiet: Option<QuietHoursRecord> =
+        sqlx::query_as("SELECT `start`, `end` FROM `quiet_hours` WHERE `channel_id` = ?;")
+            .bind(channel_id)
+            .fetch_optional(pool)
+            .await?;
+    Ok(quiet)
+}
+
+/// channel_id の投稿を控える時間帯を設定する
+pub async fn update_quiet_hours(
+    pool: &MySqlPool,
+    channel_id: &str,
+    start: NaiveTime,
+    end: NaiveTime,
+) -> anyhow::Result<()> {
+    sqlx::query("INSERT INTO `quiet_hours` (`channel_id`, `start`, `end`) VALUES (?, ?, ?) ON DUPLICATE KEY UPDATE `start` = VALUES(`start`), `end` = VALUES(`end`);")
+        .bind(channel_id)
+        .bind(start)
+        .bind(end)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// channel_id の投稿を控える時間帯を削除し、削除できたかどうかを返す
+pub async fn delete_quiet_hours(pool: &MySqlPool, channel_id: &str) -> anyhow::Result<bool> {
+    let result = sqlx::query("DELETE FROM `quiet_hours` WHERE `channel_id` = ?;")
+        .bind(channel_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// user_id が channel_id (またはすべてのチャンネル) に対して付与されている権限を取得する
+pub async fn get_user_roles(
+    pool: &MySqlPool,
+    user_id: &str,
+    channel_id: &str,
+) -> anyhow::Result<Vec<RoleRecord>> {
+    let roles: Vec<RoleRecord> =
+        sqlx::query_as("SELECT * FROM `roles` WHERE `user_id` = ? AND `channel_id` IN ('', ?);")
+            .bind(user_id)
+            .bind(channel_id)
+            .fetch_all(pool)
+            .await?;
+    Ok(roles)
+}
+
+/// channel_id (またはすべてのチャンネル) に対して付与されている権限を取得する
+pub async fn get_channel_roles(
+    pool: &MySqlPool,
+    channel_id: &str,
+) -> anyhow::Result<Vec<RoleRecord>> {
+    let roles: Vec<RoleRecord> = sqlx::query_as(
+        "SELECT * FROM `roles` WHERE `channel_id` IN ('', ?) ORDER BY `channel_id`, `user_id`;",
+    )
+    .bind(channel_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(roles)
+}
+
+/// user_id に channel_id に対する権限を付与する (すでに付与されている権限は置き換える)
+pub async fn grant_role(
+    pool: &MySqlPool,
+    user_id: &str,
+    channel_id: &str,
+    role: &str,
+) -> anyhow::Result<()> {
+    sqlx::query("INSERT INTO `roles` (`user_id`, `channel_id`, `role`) VALUES (?, ?, ?) ON DUPLICATE KEY UPDATE `role` = VALUES(`role`);")
+        .bind(user_id)
+        .bind(channel_id)
+        .bind(role)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// user_id の channel_id に対する権限を剥奪し、剥奪できたかどうかを返す
+pub async fn revoke_role(
+    pool: &MySqlPool,
+    user_id: &str,
+    channel_id: &str,
+    role: &str,
+) -> anyhow::Result<bool> {
+    let result =
+        sqlx::query("DELETE FROM `roles` WHERE `user_id` = ? AND `channel_id` = ? AND `role` = ?;")
+            .bind(user_id)
+            .bind(channel_id)
+            .bind(role)
+            .execute(pool)
+            .await?;
+    Ok(result.rows_affected() > 0)
+}
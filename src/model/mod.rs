@@ -1,2 +1,3 @@
 pub(crate) mod api;
 pub(crate) mod db;
+pub(crate) mod traq;
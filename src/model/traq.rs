@@ -0,0 +1,175 @@
+//! traQ API (v3) のリクエストとレスポンスの型
+//!
+//! API のスキーマに合わせているため、BOT が使っていないフィールドも含む
+
+use chrono::{DateTime, FixedOffset};
+use serde::{Deserialize, Serialize};
+
+/// GET /messages (メッセージ検索) のレスポンス
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageSearchResult {
+    /// 検索にヒットした件数 (10000 件を超える場合は 10000)
+    pub total_hits: usize,
+    pub hits: Vec<Message>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Message {
+    pub id: String,
+    pub user_id: String,
+    pub channel_id: String,
+    pub content: String,
+    pub created_at: DateTime<FixedOffset>,
+    pub updated_at: DateTime<FixedOffset>,
+    pub pinned: bool,
+    pub stamps: Vec<MessageStamp>,
+    #[serde(default)]
+    pub thread_id: Option<String>,
+}
+
+/// メッセージに押されたスタンプ
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageStamp {
+    pub user_id: String,
+    pub stamp_id: String,
+    pub count: i64,
+    pub created_at: DateTime<FixedOffset>,
+    pub updated_at: DateTime<FixedOffset>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(not(test), allow(dead_code))]
+pub struct Channel {
+    pub id: String,
+    pub parent_id: Option<String>,
+    pub archived: bool,
+    pub force: bool,
+    pub topic: String,
+    pub name: String,
+    pub children: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(not(test), allow(dead_code))]
+pub struct User {
+    pub id: String,
+    pub name: String,
+    pub display_name: String,
+    pub icon_file_id: String,
+    pub bot: bool,
+    /// 0: 凍結, 1: 有効, 2: 一時停止
+    pub state: i32,
+    pub updated_at: DateTime<FixedOffset>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(not(test), allow(dead_code))]
+pub struct Stamp {
+    pub id: String,
+    pub name: String,
+    pub creator_id: String,
+    pub created_at: DateTime<FixedOffset>,
+    pub updated_at: DateTime<FixedOffset>,
+    pub file_id: String,
+    pub is_unicode: bool,
+}
+
+/// POST /channels/{channelId}/messages のリクエスト
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostMessageRequest {
+    pub content: String,
+    /// メンションやチャンネルリンクを自動で埋め込みに変換するか
+    pub embed: bool,
+}
+
+/// POST /bots/{botId}/actions/join, leave のリクエスト
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BotChannelActionRequest {
+    pub channel_id: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_search_result() {
+        let result: MessageSearchResult = serde_json::from_str(include_str!(
+            "../../tests/fixtures/traq/search_messages.json"
+        ))
+        .unwrap();
+        assert_eq!(result.total_hits, 2);
+        assert_eq!(result.hits.len(), 2);
+        assert_eq!(result.hits[0].content, "おはよう :blob_pyon:");
+        assert_eq!(result.hits[0].stamps[0].count, 2);
+        assert_eq!(result.hits[1].thread_id, None);
+        assert_eq!(
+            result.hits[1].created_at.naive_utc().to_string(),
+            "2022-07-31 14:59:59.123456"
+        );
+    }
+
+    #[test]
+    fn test_message() {
+        let message: Message =
+            serde_json::from_str(include_str!("../../tests/fixtures/traq/message.json")).unwrap();
+        assert_eq!(message.user_id, "d8ff0b6c-431f-4476-9708-cb9d2e49b0a5");
+        assert!(message.stamps.is_empty());
+    }
+
+    #[test]
+    fn test_channel() {
+        let channel: Channel =
+            serde_json::from_str(include_str!("../../tests/fixtures/traq/channel.json")).unwrap();
+        assert_eq!(channel.name, "bot");
+        assert!(channel.parent_id.is_some());
+    }
+
+    #[test]
+    fn test_user() {
+        let user: User =
+            serde_json::from_str(include_str!("../../tests/fixtures/traq/user.json")).unwrap();
+        assert_eq!(user.name, "SSlime");
+        assert!(!user.bot);
+    }
+
+    #[test]
+    fn test_stamp() {
+        let stamp: Stamp =
+            serde_json::from_str(include_str!("../../tests/fixtures/traq/stamp.json")).unwrap();
+        assert_eq!(stamp.name, "blob_pyon");
+    }
+
+    #[test]
+    fn test_missing_field() {
+        let res = r#"{"totalHits":1,"hits":[{"id":"m"}]}"#;
+        assert!(serde_json::from_str::<MessageSearchResult>(res).is_err());
+    }
+
+    #[test]
+    fn test_requests() {
+        let request = PostMessageRequest {
+            content: "\"quoted\" な本文".to_string(),
+            embed: false,
+        };
+        assert_eq!(
+            serde_json::to_value(&request).unwrap(),
+            serde_json::json!({ "content": "\"quoted\" な本文", "embed": false })
+        );
+        let request = BotChannelActionRequest {
+            channel_id: "c".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(),
+            r#"{"channelId":"c"}"#
+        );
+    }
+}
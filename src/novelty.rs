@@ -0,0 +1,140 @@
+use std::collections::{HashMap, HashSet};
+
+/// 生成したメッセージが学習元のメッセージの丸写しになっていないかを判定する
+#[derive(Debug, Clone)]
+pub struct NoveltyChecker {
+    /// 学習元のメッセージと、同じ内容のメッセージの数
+    messages: HashMap<String, usize>,
+    /// 学習元のメッセージとの編集距離がこれ未満のものは丸写しとみなす
+    min_edit_distance: usize,
+    /// 学習元のメッセージと共通する最長の部分文字列が、生成したメッセージの長さのこの割合を超えるものは丸写しとみなす
+    max_overlap_ratio: f64,
+}
+
+impl NoveltyChecker {
+    pub fn new(min_edit_distance: usize, max_overlap_ratio: f64) -> Self {
+        Self {
+            messages: HashMap::new(),
+            min_edit_distance,
+            max_overlap_ratio,
+        }
+    }
+
+    /// 学習元のメッセージを追加する
+    pub fn insert(&mut self, message: String) {
+        *self.messages.entry(message).or_default() += 1;
+    }
+
+    /// 学習元のメッセージを取り除く (同じ内容のメッセージが他にもあれば、それらは残る)
+    pub fn remove(&mut self, message: &str) {
+        if let Some(count) = self.messages.get_mut(message) {
+            *count -= 1;
+            if *count == 0 {
+                self.messages.remove(message);
+            }
+        }
+    }
+
+    /// generated がどの学習元のメッセージの丸写しでもなければ true を返す
+    pub fn is_novel(&self, generated: &str) -> bool {
+        if self.min_edit_distance > 0 && self.messages.contains_key(generated) {
+            return false;
+        }
+
+        let generated = generated.chars().collect::<Vec<_>>();
+        // この長さの部分文字列が一致したら、共通部分が max_overlap_ratio を超える
+        let overlap_window = (self.max_overlap_ratio * generated.len() as f64).floor() as usize + 1;
+        let generated_windows = if overlap_window <= generated.len() {
+            generated.windows(overlap_window).collect::<HashSet<_>>()
+        } else {
+            HashSet::new()
+        };
+
+        self.messages.keys().all(|message| {
+            // 長さの差が min_edit_distance 以上なら編集距離もそれ以上なので、文字数だけで判定できる
+            let len = message.chars().count();
+            let may_be_close = len.abs_diff(generated.len()) < self.min_edit_distance;
+            let may_overlap = !generated_windows.is_empty() && overlap_window <= len;
+            if !may_be_close && !may_overlap {
+                return true;
+            }
+
+            let message = message.chars().collect::<Vec<_>>();
+            let too_close =
+                may_be_close && levenshtein(&generated, &message) < self.min_edit_distance;
+            let too_overlapped = may_overlap
+                && message
+                    .windows(overlap_window)
+                    .any(|window| generated_windows.contains(window));
+            !too_close && !too_overlapped
+        })
+    }
+}
+
+/// 文字単位の編集距離を求める
+fn levenshtein(a: &[char], b: &[char]) -> usize {
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    let mut current = vec![0; b.len() + 1];
+    for (i, ca) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev[j] + usize::from(ca != cb);
+            current[j + 1] = substitution.min(prev[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut current);
+    }
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chars(s: &str) -> Vec<char> {
+        s.chars().collect()
+    }
+
+    #[test]
+    fn test_levenshtein() {
+        assert_eq!(levenshtein(&chars("kitten"), &chars("sitting")), 3);
+        assert_eq!(levenshtein(&chars(""), &chars("abc")), 3);
+        assert_eq!(levenshtein(&chars("あいう"), &chars("あいう")), 0);
+    }
+
+    #[test]
+    fn test_exact_copy_is_not_novel() {
+        let mut checker = NoveltyChecker::new(1, 1.0);
+        checker.insert("おはようございます".to_string());
+        assert!(!checker.is_novel("おはようございます"));
+        assert!(checker.is_novel("おはようございました"));
+    }
+
+    #[test]
+    fn test_remove_duplicate() {
+        let mut checker = NoveltyChecker::new(1, 1.0);
+        checker.insert("おはようございます".to_string());
+        checker.insert("おはようございます".to_string());
+        checker.remove("おはようございます");
+        assert!(!checker.is_novel("おはようございます"));
+        checker.remove("おはようございます");
+        assert!(checker.is_novel("おはようございます"));
+    }
+
+    #[test]
+    fn test_min_edit_distance() {
+        let mut checker = NoveltyChecker::new(3, 1.0);
+        checker.insert("今日は晴れです".to_string());
+        assert!(!checker.is_novel("今日も晴れです"));
+        assert!(checker.is_novel("明日も雨でした"));
+    }
+
+    #[test]
+    fn test_max_overlap_ratio() {
+        let mut checker = NoveltyChecker::new(0, 0.5);
+        checker.insert("ねこがすき".to_string());
+        // "ねこがすき" が 5/7 文字一致している
+        assert!(!checker.is_novel("ねこがすきです"));
+        // 一致しているのは "ねこが" の 3/7 文字のみ
+        assert!(checker.is_novel("ねこがいぬです"));
+    }
+}
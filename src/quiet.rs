@@ -0,0 +1,166 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, ensure, Context as _};
+use chrono::{DateTime, NaiveTime, Utc};
+use chrono_tz::Tz;
+use sqlx::MySqlPool;
+
+use crate::{
+    model::db::{delete_quiet_hours, get_quiet_hours, update_quiet_hours},
+    QUIET_HOURS_CACHE,
+};
+
+/// 投稿を控える時間帯 (定期投稿と同じタイムゾーンの時刻で、日付をまたいでもよい)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuietHours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl QuietHours {
+    /// time が start 以上 end 未満なら true を返す
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+
+    /// tz の時刻で now が時間帯に含まれるなら true を返す
+    pub fn contains_at(&self, now: DateTime<Utc>, tz: Tz) -> bool {
+        self.contains(now.with_timezone(&tz).time())
+    }
+}
+
+impl FromStr for QuietHours {
+    type Err = anyhow::Error;
+
+    /// `01:00-07:00` の形式
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow!("expected `HH:MM-HH:MM`, found `{}`", s))?;
+        let parse = |t: &str| {
+            NaiveTime::parse_from_str(t.trim(), "%H:%M")
+                .with_context(|| format!("invalid time: {}", t.trim()))
+        };
+        let (start, end) = (parse(start)?, parse(end)?);
+        ensure!(start != end, "start and end must be different");
+        Ok(Self { start, end })
+    }
+}
+
+impl fmt::Display for QuietHours {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+/// channel_id の投稿を控える時間帯を返す
+pub async fn get_quiet_hours_with_cache(
+    pool: &MySqlPool,
+    channel_id: &str,
+) -> anyhow::Result<Option<QuietHours>> {
+    if let Some(quiet) = QUIET_HOURS_CACHE.lock().unwrap().get(channel_id) {
+        return Ok(*quiet);
+    }
+    let quiet = get_quiet_hours(pool, channel_id)
+        .await?
+        .map(|r| QuietHours {
+            start: r.start,
+            end: r.end,
+        });
+    QUIET_HOURS_CACHE
+        .lock()
+        .unwrap()
+        .insert(channel_id.to_string(), quiet);
+    Ok(quiet)
+}
+
+/// channel_id の投稿を控える時間帯を設定する (None なら解除する)
+pub async fn set_quiet_hours(
+    pool: &MySqlPool,
+    channel_id: &str,
+    quiet: Option<QuietHours>,
+) -> anyhow::Result<()> {
+    match quiet {
+        Some(quiet) => update_quiet_hours(pool, channel_id, quiet.start, quiet.end).await?,
+        None => {
+            delete_quiet_hours(pool, channel_id).await?;
+        }
+    }
+    QUIET_HOURS_CACHE
+        .lock()
+        .unwrap()
+        .insert(channel_id.to_string(), quiet);
+    Ok(())
+}
+
+/// channel_id が tz の時刻で投稿を控える時間帯なら true を返す
+///
+/// tz には定期投稿のスケジューラーと同じもの (PostScheduler::tz) を渡す
+pub async fn is_quiet(pool: &MySqlPool, channel_id: &str, tz: Tz) -> anyhow::Result<bool> {
+    let quiet = get_quiet_hours_with_cache(pool, channel_id).await?;
+    Ok(quiet.is_some_and(|q| q.contains_at(Utc::now(), tz)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let quiet: QuietHours = "01:00-07:30".parse().unwrap();
+        assert_eq!(quiet.start, time(1, 0));
+        assert_eq!(quiet.end, time(7, 30));
+        assert_eq!(quiet.to_string(), "01:00-07:30");
+        assert_eq!(
+            " 23:00 - 6:00 ".trim().parse::<QuietHours>().unwrap(),
+            QuietHours {
+                start: time(23, 0),
+                end: time(6, 0)
+            }
+        );
+
+        assert!("01:00".parse::<QuietHours>().is_err());
+        assert!("01:00-25:00".parse::<QuietHours>().is_err());
+        assert!("07:00-07:00".parse::<QuietHours>().is_err());
+    }
+
+    #[test]
+    fn test_contains() {
+        let quiet: QuietHours = "01:00-07:00".parse().unwrap();
+        assert!(!quiet.contains(time(0, 59)));
+        assert!(quiet.contains(time(1, 0)));
+        assert!(quiet.contains(time(6, 59)));
+        assert!(!quiet.contains(time(7, 0)));
+
+        // 日付をまたぐ
+        let quiet: QuietHours = "23:00-06:00".parse().unwrap();
+        assert!(quiet.contains(time(23, 30)));
+        assert!(quiet.contains(time(0, 0)));
+        assert!(!quiet.contains(time(6, 0)));
+        assert!(!quiet.contains(time(12, 0)));
+    }
+
+    #[test]
+    fn test_contains_at() {
+        let quiet: QuietHours = "01:00-07:00".parse().unwrap();
+        // 日本時間の 02:00
+        let now = DateTime::parse_from_rfc3339("2026-01-01T17:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert!(quiet.contains_at(now, chrono_tz::Asia::Tokyo));
+        assert!(!quiet.contains_at(now, chrono_tz::UTC));
+    }
+}
@@ -0,0 +1,55 @@
+use log::{error, info, warn};
+use sqlx::MySqlPool;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{
+    apply_config_changes, config, model::api::ApiClient, role::warn_if_no_owner, FREQUENCIES_CACHE,
+};
+
+/// 設定を再読み込みし、変更に応じて markov chain などを作り直す
+///
+/// 再起動しないと反映されない項目が変更されていれば、その名前を返す
+/// 変更の反映に失敗した場合は、古い設定に戻してエラーを返す
+pub async fn reload_config(pool: &MySqlPool, api: &ApiClient) -> anyhow::Result<Vec<&'static str>> {
+    let (old, new) = config::reload()?;
+    info!("config reloaded");
+
+    // 頻度が設定されていないチャンネルには default_freq がキャッシュされている
+    FREQUENCIES_CACHE.lock().unwrap().clear();
+    api.set_search_rate(new.crawl.requests_per_second);
+    if let Err(e) = apply_config_changes(pool, api, &old, &new).await {
+        // 次の再読み込みで同じ変更をやり直せるように、古い設定と、それに合った markov chain に戻す
+        config::restore(old.clone());
+        FREQUENCIES_CACHE.lock().unwrap().clear();
+        api.set_search_rate(old.crawl.requests_per_second);
+        if let Err(e) = apply_config_changes(pool, api, &new, &old).await {
+            error!("Failed to roll back config changes: {:#}", e);
+        }
+        return Err(e.context("failed to apply config changes, rolled back to the previous config"));
+    }
+
+    if let Err(e) = warn_if_no_owner(pool).await {
+        error!("Failed to check owners: {:#}", e);
+    }
+
+    let restart_required = old.restart_required_changes(&new);
+    if !restart_required.is_empty() {
+        warn!(
+            "changes to {} will take effect after restart",
+            restart_required.join(", ")
+        );
+    }
+    Ok(restart_required)
+}
+
+/// SIGHUP を受け取るたびに設定を再読み込みする
+pub async fn reload_on_hangup(pool: &MySqlPool, api: &ApiClient) -> anyhow::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        info!("SIGHUP received, reloading config...");
+        if let Err(e) = reload_config(pool, api).await {
+            error!("Failed to reload config: {:#}", e);
+        }
+    }
+    Ok(())
+}
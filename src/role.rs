@@ -0,0 +1,120 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::bail;
+use log::warn;
+use sqlx::MySqlPool;
+
+use crate::{
+    config::config,
+    model::db::{get_channel_roles, get_user_roles},
+};
+
+/// コマンドを実行するのに必要な権限
+///
+/// 上のものほど強く、強い権限は弱い権限のコマンドも実行できる
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// 全員
+    Everyone,
+    /// チャンネルの管理者 (そのチャンネルの設定を変更できる)
+    ChannelAdmin,
+    /// BOT のオーナー (すべてのチャンネルの設定と、権限の付与・剥奪ができる)
+    Owner,
+}
+
+impl Role {
+    /// DB に保存する名前
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Everyone => "everyone",
+            Role::ChannelAdmin => "admin",
+            Role::Owner => "owner",
+        }
+    }
+
+    /// 返信で使う名前
+    pub fn display_name(&self) -> &'static str {
+        match self {
+            Role::Everyone => "全員",
+            Role::ChannelAdmin => "チャンネル管理者",
+            Role::Owner => "オーナー",
+        }
+    }
+
+    /// オーナーはすべてのチャンネルに対する権限なので、チャンネルを区別しない
+    pub fn is_global(&self) -> bool {
+        *self == Role::Owner
+    }
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let role = match s {
+            "owner" => Role::Owner,
+            "admin" | "channel_admin" => Role::ChannelAdmin,
+            "everyone" => Role::Everyone,
+            _ => bail!("unknown role: {}", s),
+        };
+        Ok(role)
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// DB の roles テーブルで、すべてのチャンネルに対する権限を表す channel_id
+pub const ALL_CHANNELS: &str = "";
+
+/// user_id が channel_id で持つ最も強い権限を返す
+///
+/// 設定ファイルの `admin.user_ids` のユーザーは、DB にかかわらず常にオーナー
+pub async fn role_of(pool: &MySqlPool, user_id: &str, channel_id: &str) -> anyhow::Result<Role> {
+    if config().is_owner(user_id) {
+        return Ok(Role::Owner);
+    }
+    let role = get_user_roles(pool, user_id, channel_id)
+        .await?
+        .iter()
+        .filter_map(|r| r.role.parse::<Role>().ok())
+        .max()
+        .unwrap_or(Role::Everyone);
+    Ok(role)
+}
+
+/// オーナーが 1 人もいなければ警告する
+///
+/// オーナーがいないと、誰も `/reload` や `/role grant` を使えず、権限を付与する方法がない
+pub async fn warn_if_no_owner(pool: &MySqlPool) -> anyhow::Result<()> {
+    if !config().admin.user_ids.is_empty() {
+        return Ok(());
+    }
+    let has_owner = get_channel_roles(pool, ALL_CHANNELS)
+        .await?
+        .iter()
+        .any(|r| r.role.parse::<Role>().ok() == Some(Role::Owner));
+    if !has_owner {
+        warn!("no owner is configured: set admin.user_ids (ADMIN_USER_IDS), otherwise nobody can run /reload or grant roles with /role");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_role() {
+        assert!(Role::Owner > Role::ChannelAdmin);
+        assert!(Role::ChannelAdmin > Role::Everyone);
+        for role in [Role::Everyone, Role::ChannelAdmin, Role::Owner] {
+            assert_eq!(role.as_str().parse::<Role>().unwrap(), role);
+        }
+        assert_eq!("channel_admin".parse::<Role>().unwrap(), Role::ChannelAdmin);
+        assert!("root".parse::<Role>().is_err());
+    }
+}
@@ -0,0 +1,97 @@
+use std::net::Ipv4Addr;
+
+use anyhow::anyhow;
+use rocket::{
+    config::{LogLevel, Shutdown},
+    http::Status,
+    request::{FromRequest, Outcome},
+    Request, State,
+};
+use sqlx::MySqlPool;
+use subtle::ConstantTimeEq;
+
+use crate::{model::api::ApiClient, reload::reload_config};
+
+struct ServerState {
+    pool: &'static MySqlPool,
+    api: ApiClient,
+    /// `Authorization: Bearer {token}` で指定する必要があるトークン
+    token: String,
+}
+
+/// 正しいトークンが指定されたリクエスト
+struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(state) = request.rocket().state::<ServerState>() else {
+            return Outcome::Error((Status::InternalServerError, ()));
+        };
+        let authorized = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|v| v.strip_prefix("Bearer "))
+            // 先頭から何文字一致したかを応答時間から推測されないよう、一定時間で比較する
+            .is_some_and(|token| token.as_bytes().ct_eq(state.token.as_bytes()).into());
+        if authorized {
+            Outcome::Success(Admin)
+        } else {
+            log::warn!("unauthorized request to {}", request.uri());
+            Outcome::Error((Status::Unauthorized, ()))
+        }
+    }
+}
+
+/// 設定を再読み込みする
+#[rocket::post("/reload")]
+async fn reload(_admin: Admin, state: &State<ServerState>) -> (Status, String) {
+    match reload_config(state.pool, &state.api).await {
+        Ok(restart_required) if restart_required.is_empty() => {
+            (Status::Ok, "reloaded\n".to_string())
+        }
+        Ok(restart_required) => (
+            Status::Ok,
+            format!(
+                "reloaded (restart required for: {})\n",
+                restart_required.join(", ")
+            ),
+        ),
+        Err(e) => (Status::InternalServerError, format!("{:#}\n", e)),
+    }
+}
+
+/// 管理用の HTTP サーバーを port で起動する
+pub async fn serve(
+    pool: &'static MySqlPool,
+    api: ApiClient,
+    port: u16,
+    token: String,
+) -> anyhow::Result<()> {
+    let config = rocket::Config {
+        address: Ipv4Addr::UNSPECIFIED.into(),
+        port,
+        log_level: LogLevel::Off,
+        // シグナルは BOT 全体で扱うので、rocket だけが終了しないようにする
+        shutdown: Shutdown {
+            ctrlc: false,
+            signals: Default::default(),
+            ..Default::default()
+        },
+        ..rocket::Config::default()
+    };
+    log::info!(
+        "admin server listening on port {} (POST {})",
+        port,
+        rocket::uri!(reload)
+    );
+    let _ = rocket::custom(config)
+        .manage(ServerState { pool, api, token })
+        .mount("/", rocket::routes![reload])
+        .launch()
+        .await
+        .map_err(|e| anyhow!("failed to launch admin server: {}", e.kind()))?;
+    Ok(())
+}